@@ -0,0 +1,83 @@
+use crate::error::ParseError;
+use crate::expression::Expression;
+use crate::parser::{LabelsMap, parse_cell_from_str, Sheet};
+use crate::Spreadsheet;
+
+/// Assembles a `Spreadsheet` row by row from cell strings written in the same syntax as the
+/// text format, e.g. `!label`, `=sum(A1, B1)` or plain values.
+#[derive(Default)]
+pub struct SpreadsheetBuilder {
+    rows: Sheet,
+    labels_map: LabelsMap,
+    error: Option<ParseError>,
+}
+
+impl SpreadsheetBuilder {
+    pub fn row<S: AsRef<str>>(mut self, cells: impl IntoIterator<Item = S>) -> Self {
+        let row = cells
+            .into_iter()
+            .map(|cell| parse_cell_from_str(cell.as_ref().trim()).unwrap_or(Expression::Empty))
+            .collect();
+
+        self.push_row(row);
+        self
+    }
+
+    pub(crate) fn push_row(&mut self, row: Vec<Expression>) {
+        let row_number = self.rows.len();
+
+        for (column_number, expr) in row.iter().enumerate() {
+            if let Expression::Label(label) = expr {
+                if let Some((first_row, first_column)) = self.labels_map.insert(label.to_string(), (row_number, column_number)) {
+                    self.error.get_or_insert(ParseError::DuplicateLabel {
+                        label: label.to_string(),
+                        first: (first_row + 1, first_column + 1),
+                        second: (row_number + 1, column_number + 1),
+                    });
+                }
+            }
+        }
+
+        self.rows.push(row);
+    }
+
+    /// Panics if a label is defined twice, see `try_build`.
+    pub fn build(self) -> Spreadsheet {
+        self.try_build().unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_build(self) -> Result<Spreadsheet, ParseError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(Spreadsheet::from_parts(self.rows, self.labels_map)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ParseError;
+    use crate::Spreadsheet;
+
+    #[test]
+    fn test_build_and_evaluate() {
+        let spreadsheet = Spreadsheet::builder()
+            .row(vec!["!price", "!total"])
+            .row(vec!["2", "=A2*3"])
+            .row(vec!["", "=@total<1>+1"])
+            .build();
+
+        let evaluated = spreadsheet.evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["price", "total"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["2", "6"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["", "7"]);
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let error = Spreadsheet::builder().row(["!a", "!b"]).row(["1", "2"]).row(["", "!a"]).try_build().err().unwrap();
+
+        assert_eq!(error, ParseError::DuplicateLabel { label: "a".to_string(), first: (1, 1), second: (3, 2) });
+    }
+}
@@ -0,0 +1,57 @@
+//! Splitting of delimiter-separated text into fields, following RFC 4180: fields may be
+//! wrapped in double quotes to contain delimiters, line breaks or `""`-escaped quotes.
+
+pub(crate) fn parse_delimited(input: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_delimited() {
+        let rows = parse_delimited("a,\"b,c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",,x\n", ',');
+
+        assert_eq!(rows, vec![
+            vec!["a", "b,c", "say \"hi\""],
+            vec!["multi\nline", "", "x"],
+        ]);
+    }
+}
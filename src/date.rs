@@ -0,0 +1,135 @@
+//! Calendar helpers for the date functions, working on days since the Unix epoch in the
+//! proleptic Gregorian calendar.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Converts a civil date into the number of days since 1970-01-01.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Converts a number of days since 1970-01-01 into a civil `(year, month, day)`.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Parses a `YYYY-MM-DD` date into days since 1970-01-01, rejecting dates that don't exist.
+pub(crate) fn parse_date(input: &str) -> Option<i64> {
+    let mut parts = input.trim().splitn(3, '-');
+    let year: i64 = parse_part(parts.next()?, 4)?;
+    let month: u32 = parse_part(parts.next()?, 2)?;
+    let day: u32 = parse_part(parts.next()?, 2)?;
+
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    Some(days)
+}
+
+/// Moves a date by a whole number of days. `None` for any other offset, or when the result falls
+/// outside the years 0000 to 9999, which `YYYY-MM-DD` can't write.
+pub(crate) fn add_days(days: i64, offset: f64) -> Option<i64> {
+    if !offset.is_finite() || offset.fract() != 0.0 {
+        return None;
+    }
+    let days = days.checked_add(offset as i64)?;
+    (days_from_civil(0, 1, 1)..=days_from_civil(9999, 12, 31)).contains(&days).then_some(days)
+}
+
+fn parse_part<T: std::str::FromStr>(part: &str, digits: usize) -> Option<T> {
+    if part.len() != digits || !part.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+pub(crate) fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats a point in time as an ISO-8601 UTC timestamp, e.g. `2022-02-20T13:45:00Z`.
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs() as i64),
+    };
+    let seconds_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        format_date(seconds.div_euclid(SECONDS_PER_DAY)),
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+    )
+}
+
+/// Formats the UTC calendar date of a point in time, e.g. `2022-02-20`.
+pub(crate) fn format_day(time: SystemTime) -> String {
+    format_timestamp(time)[..10].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_civil_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-02"), Some(1));
+        assert_eq!(parse_date("2024-02-29"), Some(19_782));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2022-13-01"), None);
+        assert_eq!(parse_date("2022-2-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_add_days() {
+        assert_eq!(add_days(0, 1.0), Some(1));
+        assert_eq!(add_days(parse_date("9999-12-30").unwrap(), 1.0), parse_date("9999-12-31"));
+        assert_eq!(add_days(parse_date("9999-12-31").unwrap(), 1.0), None);
+        assert_eq!(add_days(parse_date("0000-01-01").unwrap(), -1.0), None);
+        assert_eq!(add_days(0, 1e30), None);
+        assert_eq!(add_days(0, 1.5), None);
+        assert_eq!(add_days(0, f64::NAN), None);
+        assert_eq!(add_days(0, f64::INFINITY), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_645_364_700);
+
+        assert_eq!(format_timestamp(time), "2022-02-20T13:45:00Z");
+        assert_eq!(format_day(time), "2022-02-20");
+    }
+}
@@ -0,0 +1,191 @@
+use crate::column_name_from_index;
+use crate::parser::Rule;
+
+/// Error values a cell can evaluate to. They render as the usual spreadsheet sentinels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CellError {
+    /// A label reference points to a label that isn't defined.
+    Name,
+    /// A reference points outside of the sheet, e.g. `^^` on the first row.
+    Ref,
+    /// A function got a value it can't work with, e.g. an invalid date.
+    Value,
+    /// A math function got a number outside of its domain, e.g. the square root of a negative.
+    Num,
+    /// A number was divided by zero.
+    Div,
+    /// A lookup didn't find its key.
+    NA,
+}
+
+impl std::fmt::Display for CellError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str(match self {
+            CellError::Name => "#NAME?",
+            CellError::Ref => "#REF!",
+            CellError::Value => "#VALUE!",
+            CellError::Num => "#NUM!",
+            CellError::Div => "#DIV/0!",
+            CellError::NA => "#N/A",
+        })
+    }
+}
+
+/// Something suspicious that happened while a cell was evaluated. It doesn't stop evaluation,
+/// but it often hides a mistake in the sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// 1-based position of the cell that was being evaluated.
+    pub row: usize,
+    pub column: usize,
+    pub kind: WarningKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarningKind {
+    /// Text that isn't a number was used as one, and counted as `0`.
+    TextAsNumber(String),
+    /// `A^v` was used on a column that has no values.
+    EmptyColumnReference(String),
+    /// `@label<n>` points past the end of the sheet.
+    LabelOffsetOutOfRange(String),
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}{}: ", column_name_from_index(self.column), self.row)?;
+        match &self.kind {
+            WarningKind::TextAsNumber(text) => write!(fmt, "text '{}' was used as the number 0", text),
+            WarningKind::EmptyColumnReference(column) => write!(fmt, "column {} has no values", column),
+            WarningKind::LabelOffsetOutOfRange(label) => write!(fmt, "reference to label '{}' points past the end of the sheet", label),
+        }
+    }
+}
+
+/// Errors that prevent a sheet from being parsed at all. Positions are 1-based `(row, column)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Syntax(Box<pest::error::Error<Rule>>),
+    DuplicateLabel { label: String, first: (usize, usize), second: (usize, usize) },
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        ParseError::Syntax(Box::new(error))
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Syntax(error) => error.fmt(fmt),
+            ParseError::DuplicateLabel { label, first, second } => write!(
+                fmt,
+                "label '{}' is defined twice, at {}{} and {}{}",
+                label,
+                column_name_from_index(first.1),
+                first.0,
+                column_name_from_index(second.1),
+                second.0,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Problems that stop a sheet, or a cell of it, from being evaluated. Positions are 1-based
+/// `(row, column)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpreadsheetError {
+    /// The cells reference each other in a loop, so none of them can be evaluated first.
+    Cycle { cells: Vec<(usize, usize)> },
+    DivisionByZero,
+    UnknownFunction(String),
+    WrongArity { function: String, expected: String, got: usize },
+    /// Evaluation went deeper than the recursion limit, usually because of a cell referencing
+    /// itself.
+    RecursionLimit,
+    /// A value can't be used the way a formula uses it, e.g. a list in arithmetic.
+    InvalidValue(String),
+    /// Text that isn't a number was used as one, in a strict sheet.
+    TextAsNumber(String),
+    /// A reference points to a cell that isn't there, like `Z99` in a smaller sheet or `^^` on
+    /// the first row.
+    InvalidReference(String),
+    /// A label reference names a label the sheet doesn't have.
+    UnknownLabel(String),
+}
+
+impl SpreadsheetError {
+    /// The error value a cell that failed this way evaluates to.
+    pub(crate) fn cell_error(&self) -> CellError {
+        match self {
+            SpreadsheetError::Cycle { .. } | SpreadsheetError::RecursionLimit | SpreadsheetError::InvalidReference(_) => CellError::Ref,
+            SpreadsheetError::DivisionByZero => CellError::Div,
+            SpreadsheetError::UnknownFunction(_) | SpreadsheetError::UnknownLabel(_) => CellError::Name,
+            SpreadsheetError::WrongArity { .. } | SpreadsheetError::InvalidValue(_) | SpreadsheetError::TextAsNumber(_) => CellError::Value,
+        }
+    }
+}
+
+impl std::fmt::Display for SpreadsheetError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpreadsheetError::Cycle { cells } => {
+                let cells = cells
+                    .iter()
+                    .map(|(row, column)| format!("{}{}", column_name_from_index(*column), row))
+                    .collect::<Vec<_>>();
+                write!(fmt, "circular reference between {}", cells.join(", "))
+            }
+            SpreadsheetError::DivisionByZero => fmt.write_str("division by zero"),
+            SpreadsheetError::UnknownFunction(name) => write!(fmt, "unknown function '{}'", name),
+            SpreadsheetError::WrongArity { function, expected, got } => write!(fmt, "{}() expects {} params, got {}", function, expected, got),
+            SpreadsheetError::RecursionLimit => fmt.write_str("recursion limit reached"),
+            SpreadsheetError::InvalidValue(message) => fmt.write_str(message),
+            SpreadsheetError::TextAsNumber(text) => write!(fmt, "text '{}' isn't a number", text),
+            SpreadsheetError::InvalidReference(reference) => write!(fmt, "{} doesn't refer to a cell", reference),
+            SpreadsheetError::UnknownLabel(label) => write!(fmt, "unknown label '{}'", label),
+        }
+    }
+}
+
+impl std::error::Error for SpreadsheetError {}
+
+/// Errors from loading a sheet out of a file or reader.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Parse(ParseError),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(fmt, "could not read spreadsheet: {}", error),
+            LoadError::Parse(error) => write!(fmt, "could not parse spreadsheet: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error),
+            LoadError::Parse(error) => Some(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(error: std::io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
+impl From<ParseError> for LoadError {
+    fn from(error: ParseError) -> Self {
+        LoadError::Parse(error)
+    }
+}
@@ -1,32 +1,72 @@
+use std::hash::{Hash, Hasher};
+use crate::date::{add_days, format_date, format_day, format_timestamp, parse_date};
+use crate::error::{CellError, SpreadsheetError, WarningKind};
 use crate::parser::parse_cell_from_str;
-use crate::Spreadsheet;
+use crate::{column_name_from_index, Spreadsheet};
 
-const RECURSION_LIMIT: usize = 256;
+pub(crate) const RECURSION_LIMIT: usize = 256;
+const APPROX_EPSILON: f64 = 1e-9;
+/// The longest text `repeat()` may produce, in bytes. Also bounds the padding of `pad_left()`
+/// and `pad_right()`.
+const REPEAT_LIMIT: usize = 1024 * 1024;
+/// The most decimals `format_number()` writes. More than that are digits `f64` doesn't have.
+const MAX_DECIMALS: usize = 20;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CellReference {
     pub name: String,
     pub column_name: String,
     pub column: usize,
     pub row: usize,
+    /// Set for `$A1`. The column of an absolute reference never moves when its formula is copied.
+    pub absolute_column: bool,
+    /// Set for `A$1`. The row of an absolute reference never moves when its formula is copied.
+    pub absolute_row: bool,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl CellReference {
+    fn moved_down(&self, rows: usize) -> CellReference {
+        if self.absolute_row {
+            return self.clone();
+        }
+
+        let row = self.row + rows;
+        CellReference {
+            name: format!("{}{}", self.name.trim_end_matches(|c: char| c.is_ascii_digit()), row),
+            row,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct LabelReference {
     pub label: String,
     pub n_rows: usize,
+    pub n_columns: usize,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ColumnReference {
     pub name: String,
     pub column: usize,
 }
 
+/// Whole rows, like `2:2` or `2:4`, spanning the 1-based rows `first` to `last`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RowReference {
+    pub name: String,
+    pub first: usize,
+    pub last: usize,
+}
+
+/// A parsed cell. Formulas are trees of these, with literals and references as the leaves.
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) enum Expression {
+pub enum Expression {
     Empty,
     Number(f64),
+    /// A literal like `9%`, holding the value before the percent sign.
+    Percentage(f64),
     Label(String),
     String(String),
     List { expressions: Vec<Expression> },
@@ -34,6 +74,7 @@ pub(crate) enum Expression {
     CellReference(CellReference),
     LabelReference(LabelReference),
     ColumnReference(ColumnReference),
+    RowReference(RowReference),
     CopyAbove,
     CopyEvaluated(ColumnReference),
     Function { name: String, params: Vec<Expression> },
@@ -41,155 +82,1971 @@ pub(crate) enum Expression {
     Minus { args: Vec<Expression> },
     Multiply { args: Vec<Expression> },
     Divide { args: Vec<Expression> },
+    Power { args: Vec<Expression> },
+    Negate(Box<Expression>),
+    Error(CellError),
+}
+
+// Parsed numbers are finite, so comparing them stays reflexive and expressions can key the
+// function call cache of `evaluate`.
+impl Eq for Expression {}
+
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Expression::Empty | Expression::CopyAbove => {}
+            // Adding 0 turns -0 into 0, which compare equal.
+            Expression::Number(number) | Expression::Percentage(number) => (number + 0.0).to_bits().hash(state),
+            Expression::Label(text) | Expression::String(text) => text.hash(state),
+            Expression::List { expressions: children }
+            | Expression::Spread(children)
+            | Expression::Plus { args: children }
+            | Expression::Minus { args: children }
+            | Expression::Multiply { args: children }
+            | Expression::Divide { args: children }
+            | Expression::Power { args: children } => children.hash(state),
+            Expression::CellReference(cell_ref) => cell_ref.hash(state),
+            Expression::LabelReference(label_ref) => label_ref.hash(state),
+            Expression::ColumnReference(column_ref) | Expression::CopyEvaluated(column_ref) => column_ref.hash(state),
+            Expression::RowReference(row_ref) => row_ref.hash(state),
+            Expression::Function { name, params } => {
+                name.hash(state);
+                params.hash(state);
+            }
+            Expression::Negate(expr) => expr.hash(state),
+            Expression::Error(error) => error.hash(state),
+        }
+    }
 }
 
 impl Expression {
     pub(crate) fn evaluate_recursively(&self, spreadsheet: &Spreadsheet) -> Expression {
         let mut expr = self.clone();
-        for _ in 0..RECURSION_LIMIT {
-            if matches!(expr, Expression::String(_)) {
+        for _ in 0..spreadsheet.recursion_limit {
+            // Finite numbers are final too, so a result keeps track of whether it's a number or text.
+            // Lists evaluate to themselves, so they are final as well.
+            if matches!(expr, Expression::String(_) | Expression::Error(_) | Expression::List { .. } | Expression::Spread(_))
+                || matches!(expr, Expression::Number(number) if number.is_finite()) {
                 return expr.clone();
             }
             expr = expr.evaluate(spreadsheet);
         }
-        eprintln!("{:?}", expr);
-        panic!("recursion limit reached")
+        spreadsheet.fail(SpreadsheetError::RecursionLimit)
     }
 
+    /// Evaluates one step. The results of function calls are remembered until the cell being
+    /// evaluated is done, so a call appearing twice in a formula is only computed once.
     pub(crate) fn evaluate(&self, spreadsheet: &Spreadsheet) -> Expression {
+        match self {
+            Expression::Function { .. } => self.evaluate_cached(spreadsheet),
+            _ => self.evaluate_step(spreadsheet),
+        }
+    }
+
+    fn evaluate_cached(&self, spreadsheet: &Spreadsheet) -> Expression {
+        // Relative references inside the call resolve against the cell, so it's part of the key.
+        let cell = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+        if let Some(value) = spreadsheet.subexpressions.borrow().get(&cell).and_then(|calls| calls.get(self)) {
+            return value.clone();
+        }
+        let value = self.evaluate_step(spreadsheet);
+        spreadsheet.subexpressions.borrow_mut().entry(cell).or_default().insert(self.clone(), value.clone());
+        value
+    }
+
+    fn evaluate_step(&self, spreadsheet: &Spreadsheet) -> Expression {
+        // Every reference passes through here, so the arms that need more than a call live in
+        // their own functions and keep this frame small for deeply chained sheets.
         match self {
             Expression::Empty => Expression::String(String::new()),
-            Expression::Number(number) => Expression::String(number.to_string()),
+            Expression::Number(number) if !number.is_finite() => Expression::Error(CellError::Num),
+            Expression::Number(number) => Expression::String(round_to_precision(*number).to_string()),
+            Expression::Percentage(percent) => Expression::String(format!("{}%", percent)),
             Expression::String(string) => Expression::String(string.clone()),
             Expression::Label(name) => Expression::String(name.to_string()),
-            Expression::CellReference(cell_ref) => spreadsheet.get_cell(cell_ref.row, cell_ref.column),
+            Expression::CellReference(cell_ref) => spreadsheet.evaluate_reference(cell_ref.row, cell_ref.column),
+            Expression::LabelReference(_)
+            | Expression::CopyAbove
+            | Expression::CopyEvaluated(_)
+            | Expression::ColumnReference(_)
+            | Expression::RowReference(_) => self.resolve_reference(spreadsheet),
+            Expression::Plus { .. }
+            | Expression::Minus { .. }
+            | Expression::Negate(_)
+            | Expression::Multiply { .. }
+            | Expression::Divide { .. }
+            | Expression::Power { .. } => self.calculate(spreadsheet),
+            Expression::Function { name, params } => evaluate_function(spreadsheet, name, params),
+            Expression::List { expressions: _ } => self.clone(),
+            Expression::Spread(_) => self.clone(),
+            Expression::Error(_) => self.clone(),
+        }
+    }
+
+    fn resolve_reference(&self, spreadsheet: &Spreadsheet) -> Expression {
+        match self {
             Expression::LabelReference(label_ref) => {
-                if let Some((label_row_number, label_column_number)) = spreadsheet.labels_map.get(&label_ref.label) {
-                    return spreadsheet.get_cell(label_row_number + label_ref.n_rows + 1, label_column_number + 1).evaluate(spreadsheet);
+                let Some((label_row_number, label_column_number)) = spreadsheet.labels_map.get(&label_ref.label) else {
+                    return spreadsheet.fail(SpreadsheetError::UnknownLabel(label_ref.label.clone()));
+                };
+                let position = offset_from_label((*label_row_number, *label_column_number), label_ref.n_rows, label_ref.n_columns);
+                match position.filter(|&(row_number, column_number)| spreadsheet.get_cell(row_number, column_number).is_some()) {
+                    Some((row_number, column_number)) => spreadsheet.evaluate_reference(row_number, column_number),
+                    None => {
+                        spreadsheet.warn(WarningKind::LabelOffsetOutOfRange(label_ref.label.clone()));
+                        spreadsheet.fail(SpreadsheetError::InvalidReference(self.to_formula()))
+                    }
                 }
-                Expression::String("error".to_string())
             }
             Expression::CopyAbove => {
-                spreadsheet.evaluating_row.replace_with(|&mut row_number| row_number - 1);
-                let above_cell = spreadsheet.get_cell(spreadsheet.evaluating_row.borrow().clone(), spreadsheet.evaluating_column.borrow().clone());
-                if matches!(above_cell, Expression::CopyAbove) {
-                    if let Expression::CellReference(cell_ref) = above_cell.evaluate(spreadsheet) {
-                        return Expression::CellReference(CellReference {
-                            name: format!("{}{}", cell_ref.column_name, cell_ref.row).to_string(),
-                            column_name: cell_ref.column_name.to_string(),
-                            column: cell_ref.column,
-                            row: cell_ref.row - 1,
-                        });
-                    }
-                    unreachable!()
+                let (row_number, column_number) = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+                copy_above_source(spreadsheet, row_number, column_number)
+                    .unwrap_or_else(|| spreadsheet.fail(SpreadsheetError::InvalidReference("^^".to_string())))
+            }
+            Expression::CopyEvaluated(column_ref) => {
+                let row_number = *spreadsheet.evaluating_row.borrow();
+                spreadsheet.evaluate_reference(row_number - 1, column_ref.column)
+            }
+            Expression::ColumnReference(column_ref) => match last_row_in_column(spreadsheet, column_ref.column) {
+                Some(row_number) => spreadsheet.evaluate_reference(row_number, column_ref.column),
+                None => {
+                    spreadsheet.warn(WarningKind::EmptyColumnReference(column_ref.name.clone()));
+                    Expression::String("error".to_string())
+                }
+            },
+            // Rows are numbered from 1, the grammar accepts `0:1` all the same.
+            Expression::RowReference(row_ref) if row_ref.first == 0 => spreadsheet.fail(SpreadsheetError::InvalidReference(row_ref.name.clone())),
+            // Spreads into the populated cells of the rows, so `sum(2:2)` adds a whole row.
+            Expression::RowReference(row_ref) => Expression::Spread(
+                populated_cells_in_rows(spreadsheet, row_ref)
+                    .into_iter()
+                    .map(|(row_number, column_number)| spreadsheet.evaluate_reference(row_number, column_number))
+                    .collect(),
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn calculate(&self, spreadsheet: &Spreadsheet) -> Expression {
+        match self {
+            Expression::Plus { args } => operands(spreadsheet, args).map_or_else(Expression::Error, |numbers| Expression::Number(numbers.iter().sum())),
+            Expression::Minus { args } => operands(spreadsheet, args)
+                .map_or_else(Expression::Error, |numbers| Expression::Number(numbers[1..].iter().fold(numbers[0], |acc, cur| acc - cur))),
+            // A negated percentage stays one, so `=-9%` shows `-9%` rather than `-0.09`.
+            Expression::Negate(expr) => match expr.evaluate(spreadsheet) {
+                Expression::Error(error) => Expression::Error(error),
+                value => match value.as_percentage(spreadsheet) {
+                    Some(percent) => Expression::Percentage(-percent),
+                    None => Expression::Number(-value.to_number(spreadsheet)),
+                },
+            },
+            Expression::Multiply { args } => operands(spreadsheet, args).map_or_else(Expression::Error, |numbers| Expression::Number(numbers.iter().product())),
+            Expression::Divide { args } => operands(spreadsheet, args).map_or_else(Expression::Error, |numbers| {
+                if numbers[1..].contains(&0.0) {
+                    return spreadsheet.fail(SpreadsheetError::DivisionByZero);
+                }
+                Expression::Number(numbers[1..].iter().fold(numbers[0], |acc, cur| acc / cur))
+            }),
+            Expression::Power { args } => operands(spreadsheet, args)
+                .map_or_else(Expression::Error, |numbers| Expression::Number(numbers[1..].iter().fold(numbers[0], |acc, cur| acc.powf(*cur)))),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Reads the expression as a number: numbers, and text that parses as one.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Expression::Number(number) => Some(*number),
+            Expression::Percentage(percent) => Some(percent / 100.0),
+            Expression::String(string) => string.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Collects the 1-based positions of the cells this expression reads when it's evaluated at
+    /// the given position. Cells found only at evaluation time, like those of `lookup()` or `vlookup()`, are
+    /// not included.
+    pub(crate) fn references(&self, spreadsheet: &Spreadsheet, row_number: usize, column_number: usize, positions: &mut Vec<(usize, usize)>) {
+        match self {
+            Expression::CellReference(cell_ref) => positions.push((cell_ref.row, cell_ref.column)),
+            Expression::LabelReference(label_ref) => {
+                if let Some(&label_position) = spreadsheet.labels_map.get(&label_ref.label) {
+                    positions.extend(offset_from_label(label_position, label_ref.n_rows, label_ref.n_columns));
                 }
-                above_cell
             }
-            Expression::CopyEvaluated(column_ref) => spreadsheet.get_cell(spreadsheet.evaluating_row.borrow().clone() - 1, column_ref.column).evaluate(spreadsheet),
+            Expression::CopyAbove => {
+                if let Some(source) = copy_above_source(spreadsheet, row_number, column_number) {
+                    source.references(spreadsheet, row_number, column_number, positions);
+                }
+            }
+            Expression::CopyEvaluated(column_ref) => positions.push((row_number - 1, column_ref.column)),
             Expression::ColumnReference(column_ref) => {
-                for row in spreadsheet.rows.iter().rev() {
-                    if let Some(cell) = row.get(column_ref.column - 1) {
-                        match cell {
-                            Expression::Empty | Expression::Label(_) => {}
-                            expr => return expr.evaluate(spreadsheet)
-                        }
-                    }
+                if let Some(last_row_number) = last_row_in_column(spreadsheet, column_ref.column) {
+                    positions.push((last_row_number, column_ref.column));
                 }
-                Expression::String("error".to_string())
-            }
-            Expression::Plus { args } => Expression::Number(args.iter().fold(0.0, |acc, cur| acc + cur.evaluate(spreadsheet).to_number())),
-            Expression::Minus { args } => {
-                let first = args[0].evaluate(spreadsheet).to_number();
-                Expression::Number(args[1..].iter().fold(first, |acc, cur| acc + cur.evaluate(spreadsheet).to_number()))
-            }
-            Expression::Multiply { args } => Expression::Number(args.iter().fold(1.0, |acc, cur| acc * cur.evaluate(spreadsheet).to_number())),
-            Expression::Divide { args } => {
-                let first = args[0].evaluate(spreadsheet).to_number();
-                Expression::Number(args[1..].iter().fold(first, |acc, cur| {
-                    let value = cur.evaluate(spreadsheet).to_number();
-                    if value == 0.0 {
-                        panic!("division by zero");
-                    }
-                    acc / value
-                }))
             }
+            Expression::RowReference(row_ref) => positions.extend(populated_cells_in_rows(spreadsheet, row_ref)),
+            Expression::List { expressions: children }
+            | Expression::Spread(children)
+            | Expression::Function { params: children, .. }
+            | Expression::Plus { args: children }
+            | Expression::Minus { args: children }
+            | Expression::Multiply { args: children }
+            | Expression::Divide { args: children }
+            | Expression::Power { args: children } => {
+                for child in children {
+                    child.references(spreadsheet, row_number, column_number, positions);
+                }
+            }
+            Expression::Negate(expr) => expr.references(spreadsheet, row_number, column_number, positions),
+            _ => {}
+        }
+    }
+
+    /// Renders the expression as an indented tree, one node per line, to show how a formula was
+    /// parsed.
+    pub fn explain(&self) -> String {
+        let mut lines = vec![];
+        self.explain_into(0, &mut lines);
+        lines.join("\n")
+    }
+
+    fn explain_into(&self, depth: usize, lines: &mut Vec<String>) {
+        let (node, children): (String, &[Expression]) = match self {
+            Expression::Empty => ("empty".to_string(), &[]),
+            Expression::Number(number) => (format!("number {}", number), &[]),
+            Expression::Percentage(percent) => (format!("percentage {}%", percent), &[]),
+            Expression::Label(name) => (format!("label !{}", name), &[]),
+            Expression::String(string) => (format!("string {:?}", string), &[]),
+            Expression::List { expressions } => ("list".to_string(), expressions),
+            Expression::Spread(expressions) => ("spread".to_string(), expressions),
+            Expression::CellReference(cell_ref) => (format!("reference {}", cell_ref.name), &[]),
+            Expression::LabelReference(label_ref) => (format!("label reference @{}<{},{}>", label_ref.label, label_ref.n_rows, label_ref.n_columns), &[]),
+            Expression::ColumnReference(column_ref) => (format!("column reference {}^v", column_ref.name), &[]),
+            Expression::RowReference(row_ref) => (format!("row reference {}", row_ref.name), &[]),
+            Expression::CopyAbove => ("copy above ^^".to_string(), &[]),
+            Expression::CopyEvaluated(column_ref) => (format!("copy evaluated {}^", column_ref.name), &[]),
+            Expression::Function { name, params } => (format!("{}()", name), params),
+            Expression::Plus { args } => ("+".to_string(), args),
+            Expression::Minus { args } => ("-".to_string(), args),
+            Expression::Multiply { args } => ("*".to_string(), args),
+            Expression::Divide { args } => ("/".to_string(), args),
+            Expression::Power { args } => ("^".to_string(), args),
+            Expression::Negate(expr) => ("negate".to_string(), std::slice::from_ref(expr.as_ref())),
+            Expression::Error(error) => (format!("error {}", error), &[]),
+        };
+
+        lines.push(format!("{}{}", "  ".repeat(depth), node));
+        for child in children {
+            child.explain_into(depth + 1, lines);
+        }
+    }
+
+    /// Returns the cell as it's written in a sheet: `!label`, `=formula`, or the literal value,
+    /// quoted when it would otherwise be read as something else.
+    pub(crate) fn to_source(&self) -> String {
+        match self {
+            Expression::Empty => String::new(),
+            Expression::Label(name) => format!("!{}", name),
+            Expression::Number(number) => number.to_string(),
+            Expression::Percentage(percent) => format!("{}%", percent),
+            Expression::String(text) if is_plain_text(text) => text.clone(),
+            Expression::String(text) => quoted(text),
+            formula => format!("={}", formula.to_formula()),
+        }
+    }
+
+    /// Returns the formula this expression is parsed from, without the leading `=`. Parentheses
+    /// are only added where precedence needs them, so `(1+2)*3` stays as written but `(1*2)+3`
+    /// becomes `1*2+3`. Parsing the result gives back the same expression.
+    pub fn to_formula(&self) -> String {
+        let operation = |operator: &str, args: &[Expression], right_associative: bool| {
+            let precedence = self.precedence();
+            args.iter()
+                .enumerate()
+                .map(|(index, arg)| {
+                    // Operators group from the left, except `^`, so only that side may go without
+                    // parentheses at the same precedence.
+                    let grouping_side = if right_associative { index == args.len() - 1 } else { index == 0 };
+                    let min_precedence = if grouping_side { precedence } else { precedence + 1 };
+                    arg.to_operand(min_precedence)
+                })
+                .collect::<Vec<_>>()
+                .join(operator)
+        };
+
+        match self {
+            Expression::Empty => "\"\"".to_string(),
+            Expression::Number(number) => number.to_string(),
+            Expression::Percentage(percent) => format!("{}%", percent),
+            Expression::Label(name) => format!("!{}", name),
+            Expression::String(text) => quoted(text),
+            // Lists only come out of evaluation, and are written as the spread of their items.
+            Expression::List { expressions } | Expression::Spread(expressions) => {
+                let items = expressions.iter().map(Expression::to_formula).collect::<Vec<_>>();
+                format!("spread({})", items.join(", "))
+            }
+            Expression::CellReference(cell_ref) => cell_ref.name.clone(),
+            Expression::LabelReference(label_ref) if label_ref.n_columns == 0 => format!("@{}<{}>", label_ref.label, label_ref.n_rows),
+            Expression::LabelReference(label_ref) => format!("@{}<{},{}>", label_ref.label, label_ref.n_rows, label_ref.n_columns),
+            Expression::ColumnReference(column_ref) => format!("{}^v", column_ref.name),
+            Expression::RowReference(row_ref) => row_ref.name.clone(),
+            Expression::CopyAbove => "^^".to_string(),
+            Expression::CopyEvaluated(column_ref) => format!("{}^", column_ref.name),
             Expression::Function { name, params } => {
-                let params: Vec<Expression> = params
-                    .iter()
-                    .flat_map(|expr| match expr.evaluate(spreadsheet) {
-                        Expression::Spread(ref expressions) => expressions.clone(),
-                        expr => vec![expr.clone()]
-                    }).collect();
-
-                match name.to_lowercase().as_str() {
-                    "sum" => Expression::Number(params.iter().fold(0.0, |acc, cur| acc + cur.evaluate(spreadsheet).to_number())
-                    ),
-                    "gte" | "bte" => {
-                        if params.len() != 2 {
-                            panic!("binary operation needs 2 params")
-                        }
-                        Expression::String((params[0].evaluate(spreadsheet).to_number() >= params[1].evaluate(spreadsheet).to_number()).to_string())
-                    }
-                    "lte" => {
-                        if params.len() != 2 {
-                            panic!("binary operation needs 2 params")
-                        }
-                        Expression::String((params[0].evaluate(spreadsheet).to_number() <= params[1].evaluate(spreadsheet).to_number()).to_string())
-                    }
-                    "text" => Expression::String(params[0].evaluate(spreadsheet).to_string()),
-                    "split" => {
-                        if params.len() != 2 {
-                            panic!("binary operation needs 2 params")
-                        }
-                        let text = params[0].evaluate(spreadsheet).to_string();
-                        let delim = params[1].evaluate(spreadsheet).to_string();
-                        let list = text.split(&delim).map(|input| {
-                            parse_cell_from_str(input).unwrap_or(Expression::String(input.to_string()))
-                        }).collect::<Vec<Expression>>();
-                        Expression::List { expressions: list }
-                    }
-                    "concat" => Expression::String(params.iter().fold(String::new(), |mut acc, cur| {
-                        acc.push_str(&cur.evaluate(spreadsheet).to_string());
-                        acc
-                    })),
-                    "spread" => Expression::Spread(match params[0].evaluate(spreadsheet) {
-                        Expression::List { expressions } => expressions.clone(),
-                        _ => panic!("spread only works on lists")
-                    }),
-                    "incfrom" => Expression::Number(params[0].evaluate(spreadsheet).to_number()),
-                    function_name => panic!("unknown function '{}'", function_name),
+                let params = params.iter().map(Expression::to_formula).collect::<Vec<_>>();
+                format!("{}({})", name, params.join(", "))
+            }
+            Expression::Plus { args } => operation("+", args, false),
+            Expression::Minus { args } => operation("-", args, false),
+            Expression::Multiply { args } => operation("*", args, false),
+            Expression::Divide { args } => operation("/", args, false),
+            Expression::Power { args } => operation("^", args, true),
+            Expression::Negate(expr) => format!("-{}", expr.to_operand(self.precedence())),
+            Expression::Error(error) => format!("\"{}\"", error),
+        }
+    }
+
+    /// Like `to_formula`, but wrapped in parentheses when the expression binds less tightly than
+    /// `min_precedence`.
+    fn to_operand(&self, min_precedence: u8) -> String {
+        if self.precedence() < min_precedence {
+            format!("({})", self.to_formula())
+        } else {
+            self.to_formula()
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Plus { .. } | Expression::Minus { .. } => 1,
+            Expression::Multiply { .. } | Expression::Divide { .. } => 2,
+            Expression::Power { .. } => 3,
+            // A negated term binds tighter than any operator, `-2^2` is `(-2)^2`.
+            _ => 4,
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        match self {
+            Expression::Empty => true,
+            Expression::String(string) => string.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        match self {
+            Expression::Number(_) | Expression::Percentage(_) => true,
+            Expression::String(string) => string.parse::<f64>().is_ok_and(f64::is_finite),
+            _ => false,
+        }
+    }
+
+    /// Like `to_string`, but returns string values as they are instead of reformatting numeric text.
+    fn to_raw_string(&self) -> String {
+        match self {
+            Expression::String(string) => string.clone(),
+            expr => expr.to_string(),
+        }
+    }
+
+    /// Returns this formula as it should be evaluated when copied `rows` rows down with `^^`.
+    /// Relative cell references move down with it, and every `incFrom(start, step)` is advanced by
+    /// `step` (1 by default) for each row, so copied formulas keep counting.
+    fn copied_down(&self, rows: usize) -> Expression {
+        let copy_all = |expressions: &Vec<Expression>| expressions.iter().map(|expr| expr.copied_down(rows)).collect::<Vec<_>>();
+
+        match self {
+            Expression::Function { name, params } if name.eq_ignore_ascii_case("incfrom") => {
+                let mut params = copy_all(params);
+                let increment = match params.get(1) {
+                    Some(step) => Expression::Multiply { args: vec![Expression::Number(rows as f64), step.clone()] },
+                    None => Expression::Number(rows as f64),
+                };
+                if let Some(start) = params.first_mut() {
+                    *start = Expression::Plus { args: vec![start.clone(), increment] };
                 }
+                Expression::Function { name: name.clone(), params }
             }
-            Expression::List { expressions: _ } => self.clone(),
-            Expression::Spread(_) => self.clone(),
+            Expression::Function { name, params } => Expression::Function { name: name.clone(), params: copy_all(params) },
+            Expression::List { expressions } => Expression::List { expressions: copy_all(expressions) },
+            Expression::Spread(expressions) => Expression::Spread(copy_all(expressions)),
+            Expression::Plus { args } => Expression::Plus { args: copy_all(args) },
+            Expression::Minus { args } => Expression::Minus { args: copy_all(args) },
+            Expression::Multiply { args } => Expression::Multiply { args: copy_all(args) },
+            Expression::Divide { args } => Expression::Divide { args: copy_all(args) },
+            Expression::Power { args } => Expression::Power { args: copy_all(args) },
+            Expression::Negate(expr) => Expression::Negate(Box::new(expr.copied_down(rows))),
+            Expression::CellReference(cell_ref) => Expression::CellReference(cell_ref.moved_down(rows)),
+            expr => expr.clone(),
         }
     }
 
-    fn to_number(&self) -> f64 {
+    /// Reads a percentage, written either as a literal or as text like `9%`, as its number of
+    /// percents.
+    fn as_percentage(&self, spreadsheet: &Spreadsheet) -> Option<f64> {
         match self {
+            Expression::Percentage(percent) => Some(*percent),
+            Expression::String(string) => spreadsheet.parse_number(string.strip_suffix('%')?),
+            _ => None,
+        }
+    }
+
+    fn to_number(&self, spreadsheet: &Spreadsheet) -> f64 {
+        match self {
+            Expression::Empty => 0.0,
             Expression::Number(number) => *number,
-            Expression::String(string) => string.parse::<f64>().unwrap_or(0.0),
+            Expression::Percentage(percent) => percent / 100.0,
+            Expression::String(string) if string == "true" => 1.0,
+            Expression::String(string) if string == "false" => 0.0,
+            Expression::String(string) => match string.strip_suffix('%') {
+                Some(percent) => spreadsheet.parse_number(percent).map(|percent| percent / 100.0).unwrap_or(0.0),
+                None => spreadsheet.parse_number(string).unwrap_or_else(|| {
+                    if string.is_empty() {
+                        return 0.0;
+                    }
+                    if spreadsheet.strict {
+                        spreadsheet.fail(SpreadsheetError::TextAsNumber(string.clone()));
+                    } else {
+                        spreadsheet.warn(WarningKind::TextAsNumber(string.clone()));
+                    }
+                    0.0
+                }),
+            },
             Expression::Spread(_) => 0.0,
-            _ => panic!("expected number")
+            // Operators and function calls return error values before reading them as numbers.
+            Expression::Error(_) => 0.0,
+            value => {
+                spreadsheet.fail(SpreadsheetError::InvalidValue(format!("expected a number, got {:?}", value)));
+                0.0
+            }
+        }
+    }
+}
+
+/// Calls a function, built-in or registered with `register_function`.
+fn evaluate_function(spreadsheet: &Spreadsheet, name: &str, params: &[Expression]) -> Expression {
+    let function_name = name.to_lowercase();
+    if takes_unevaluated_params(&function_name) && !spreadsheet.functions.contains_key(&function_name) {
+        if let Err(error) = check_arity(&function_name, params) {
+            return spreadsheet.fail(error);
+        }
+        return call_with_unevaluated_params(spreadsheet, &function_name, params);
+    }
+
+    let mut evaluated = Vec::with_capacity(params.len());
+    for param in params {
+        match param.evaluate(spreadsheet) {
+            Expression::Spread(expressions) => evaluated.extend(expressions),
+            value => evaluated.push(value),
+        }
+    }
+    let params = evaluated;
+
+    if let Some(function) = spreadsheet.functions.get(&function_name) {
+        let params = params.iter().map(|param| param.evaluate_recursively(spreadsheet)).collect::<Vec<_>>();
+        return function(&params);
+    }
+    if let Err(error) = check_arity(&function_name, &params) {
+        return spreadsheet.fail(error);
+    }
+    // Errors are contagious, except to the functions telling what kind of value they got.
+    if !matches!(function_name.as_str(), "isblank" | "isnumber" | "istext") {
+        if let Some(error) = params.iter().find(|param| matches!(param, Expression::Error(_))) {
+            return error.clone();
+        }
+    }
+
+    call_builtin(spreadsheet, &function_name, &params)
+}
+
+/// Calls one of the built-in functions in `takes_unevaluated_params`.
+fn call_with_unevaluated_params(spreadsheet: &Spreadsheet, function_name: &str, params: &[Expression]) -> Expression {
+    match function_name {
+        "if" => {
+            let condition = params[0].evaluate_recursively(spreadsheet);
+            if let Expression::Error(error) = condition {
+                return Expression::Error(error);
+            }
+            let branch = if condition.to_number(spreadsheet) != 0.0 { params.get(1) } else { params.get(2) };
+            branch.map_or(Expression::String("false".to_string()), |branch| branch.evaluate(spreadsheet))
+        }
+        "switch" => switch(spreadsheet, &params[0], &params[1..]),
+        "vlookup" => vlookup(spreadsheet, &params[0], &params[1], &params[2]),
+        "hlookup" => hlookup(spreadsheet, &params[0], &params[1], &params[2]),
+        "match" => match_position(spreadsheet, &params[0], &params[1]),
+        "concat_column" => concat_column(spreadsheet, &params[0], params.get(1)),
+        "cumsum" => cumsum(spreadsheet, &params[0]),
+        "countblank" => countblank(spreadsheet, &params[0]),
+        "weighted_average" => weighted_average(spreadsheet, &params[0], &params[1]),
+        _ => index(spreadsheet, &params[0], &params[1]),
+    }
+}
+
+/// Calls a built-in function with its params evaluated and spreads expanded.
+fn call_builtin(spreadsheet: &Spreadsheet, function_name: &str, params: &[Expression]) -> Expression {
+    match function_name {
+        "sum" => numbers_of(spreadsheet, params).map_or_else(Expression::Error, |numbers| Expression::Number(numbers.iter().sum())),
+        "gte" | "bte" | "lte" => {
+            let matches = compare(function_name, params[0].evaluate(spreadsheet).to_number(spreadsheet), params[1].evaluate(spreadsheet).to_number(spreadsheet));
+            Expression::String(matches.unwrap().to_string())
+        }
+        "approx" => {
+            let epsilon = params.get(2).map_or(APPROX_EPSILON, |epsilon| epsilon.evaluate(spreadsheet).to_number(spreadsheet));
+            let difference = params[0].evaluate(spreadsheet).to_number(spreadsheet) - params[1].evaluate(spreadsheet).to_number(spreadsheet);
+            Expression::String((difference.abs() <= epsilon).to_string())
+        }
+        "text" => match params.get(1) {
+            Some(format) => {
+                let format = format.evaluate(spreadsheet).to_raw_string();
+                match format_number(params[0].evaluate(spreadsheet).to_number(spreadsheet), &format) {
+                    Some(text) => Expression::String(text),
+                    None => spreadsheet.fail(SpreadsheetError::InvalidValue(format!("unsupported number format '{}'", format))),
+                }
+            }
+            None => Expression::String(params[0].evaluate(spreadsheet).to_string()),
+        },
+        "format_number" => {
+            let decimals = params[1].evaluate(spreadsheet).to_number(spreadsheet);
+            if !(0.0..=MAX_DECIMALS as f64).contains(&decimals) {
+                return Expression::Error(CellError::Value);
+            }
+            Expression::String(format_with_separators(
+                params[0].evaluate(spreadsheet).to_number(spreadsheet),
+                decimals as usize,
+                &params[2].evaluate(spreadsheet).to_raw_string(),
+                &params[3].evaluate(spreadsheet).to_raw_string(),
+            ))
+        }
+        "split" => {
+            let text = params[0].evaluate(spreadsheet).to_string();
+            let delim = params[1].evaluate(spreadsheet).to_string();
+            list_of_tokens(text.split(&delim))
+        }
+        "split_trim" => {
+            let text = params[0].evaluate(spreadsheet).to_string();
+            let delim = params[1].evaluate(spreadsheet).to_string();
+            list_of_tokens(text.split(&delim).map(str::trim))
+        }
+        // Splits on every character of the second param. Runs of them count as a single
+        // separator, so `split_any("a, b;c", ",; ")` gives three tokens.
+        "split_any" => {
+            let text = params[0].evaluate(spreadsheet).to_raw_string();
+            let delimiters = params[1].evaluate(spreadsheet).to_raw_string();
+            list_of_tokens(text.split(|c| delimiters.contains(c)).filter(|token| !token.is_empty()))
+        }
+        "concat" => Expression::String(params.iter().fold(String::new(), |mut acc, cur| {
+            acc.push_str(&cur.evaluate(spreadsheet).to_string());
+            acc
+        })),
+        // Unlike `concat`, keeps numbers at full precision instead of rounding them to two
+        // decimals for display, so `concat_raw("p", 1.0003)` gives `p1.0003` where `concat`
+        // gives `p1`.
+        "concat_raw" => Expression::String(params.iter().fold(String::new(), |mut acc, cur| {
+            acc.push_str(&cur.evaluate(spreadsheet).to_raw_string());
+            acc
+        })),
+        "textjoin" => {
+            let separator = params[0].evaluate(spreadsheet).to_raw_string();
+            let skip_empty = params[1].evaluate(spreadsheet).to_number(spreadsheet) != 0.0;
+            let texts = flatten_lists(&params[2..])
+                .iter()
+                .map(|param| param.evaluate(spreadsheet).to_string())
+                .filter(|text| !(skip_empty && text.is_empty()))
+                .collect::<Vec<_>>();
+            Expression::String(texts.join(&separator))
+        }
+        // Scalars spread into themselves, and a spread value already arrives as separate params,
+        // so spreading twice changes nothing.
+        "spread" => Expression::Spread(flatten_lists(params)),
+        "upper" => Expression::String(params[0].evaluate(spreadsheet).to_raw_string().to_uppercase()),
+        "lower" => Expression::String(params[0].evaluate(spreadsheet).to_raw_string().to_lowercase()),
+        // Nothing at all gives 0.
+        "min" | "max" => {
+            let numbers = match numbers_of(spreadsheet, params) {
+                Ok(numbers) => numbers,
+                Err(error) => return Expression::Error(error),
+            };
+            let extreme = match function_name {
+                "min" => numbers.into_iter().reduce(f64::min),
+                _ => numbers.into_iter().reduce(f64::max),
+            };
+            Expression::Number(extreme.unwrap_or(0.0))
+        }
+        // `variance` and `stdev` treat the numbers as a sample of a larger population, so
+        // they need at least two. `varp` and `stdevp` treat them as the whole population.
+        "variance" | "stdev" | "varp" | "stdevp" => {
+            let numbers = match numbers_of(spreadsheet, params) {
+                Ok(numbers) => numbers,
+                Err(error) => return Expression::Error(error),
+            };
+            let sample = matches!(function_name, "variance" | "stdev");
+            let degrees_of_freedom = numbers.len() as f64 - if sample { 1.0 } else { 0.0 };
+            if degrees_of_freedom < 1.0 {
+                return spreadsheet.fail(SpreadsheetError::DivisionByZero);
+            }
+            let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+            let variance = numbers.iter().map(|number| (number - mean).powi(2)).sum::<f64>() / degrees_of_freedom;
+            match function_name {
+                "variance" | "varp" => Expression::Number(variance),
+                _ => Expression::Number(variance.sqrt()),
+            }
+        }
+        "abs" => Expression::Number(params[0].evaluate(spreadsheet).to_number(spreadsheet).abs()),
+        "sqrt" | "exp" | "ln" => {
+            let value = params[0].evaluate(spreadsheet).to_number(spreadsheet);
+            match function_name {
+                "sqrt" if value < 0.0 => Expression::Error(CellError::Num),
+                "ln" if value <= 0.0 => Expression::Error(CellError::Num),
+                "sqrt" => Expression::Number(value.sqrt()),
+                "exp" => Expression::Number(value.exp()),
+                _ => Expression::Number(value.ln()),
+            }
+        }
+        "sign" => {
+            let value = params[0].evaluate(spreadsheet).to_number(spreadsheet);
+            Expression::Number(if value == 0.0 { 0.0 } else { value.signum() })
+        }
+        // Bounds are swapped when given in the wrong order, so `clamp(x, 10, 0)` is `clamp(x, 0, 10)`.
+        "clamp" => {
+            let value = params[0].evaluate(spreadsheet).to_number(spreadsheet);
+            let (low, high) = (params[1].evaluate(spreadsheet).to_number(spreadsheet), params[2].evaluate(spreadsheet).to_number(spreadsheet));
+            Expression::Number(value.clamp(low.min(high), low.max(high)))
+        }
+        "map" => {
+            // Spread lists arrive as separate params, so the function name is always the last one.
+            let Some((function_name, items)) = params.split_last() else {
+                unreachable!()
+            };
+            let function_name = function_name.evaluate(spreadsheet).to_raw_string();
+            let expressions = flatten_lists(items)
+                .into_iter()
+                .map(|item| Expression::Function { name: function_name.clone(), params: vec![item] }.evaluate(spreadsheet))
+                .collect();
+            Expression::List { expressions }
+        }
+        // The reducer is either an arithmetic operator (`+`, `-`, `*`, `/`, `^`) or the name of
+        // any function that accepts two params, like `sum` or `concat`.
+        "reduce" => {
+            let [items @ .., reducer, initial] = params else {
+                unreachable!()
+            };
+            let reducer = reducer.evaluate(spreadsheet).to_raw_string();
+            flatten_lists(items).into_iter().fold(initial.evaluate(spreadsheet), |acc, item| {
+                let args = vec![acc, item];
+                match reducer.as_str() {
+                    "+" => Expression::Plus { args },
+                    "-" => Expression::Minus { args },
+                    "*" => Expression::Multiply { args },
+                    "/" => Expression::Divide { args },
+                    "^" => Expression::Power { args },
+                    name => Expression::Function { name: name.to_string(), params: args },
+                }.evaluate(spreadsheet)
+            })
+        }
+        // Checks substring containment for two strings and membership for anything list-like.
+        "contains" => {
+            let [items @ .., needle] = params else {
+                unreachable!()
+            };
+            let needle = needle.evaluate(spreadsheet).to_string();
+            let found = match items {
+                [Expression::List { .. }] => flatten_lists(items).iter().any(|item| item.evaluate(spreadsheet).to_string() == needle),
+                [text] => text.evaluate(spreadsheet).to_raw_string().contains(&needle),
+                items => items.iter().any(|item| item.evaluate(spreadsheet).to_string() == needle),
+            };
+            Expression::String(found.to_string())
+        }
+        // Aggregates only the items for which `compare(operator, item, threshold)` holds.
+        "sumif" | "countif" => {
+            let [items @ .., operator, threshold] = params else {
+                unreachable!()
+            };
+            let operator = operator.evaluate(spreadsheet).to_raw_string();
+            let threshold = threshold.evaluate(spreadsheet).to_number(spreadsheet);
+            let mut matching = vec![];
+            for item in flatten_lists(items) {
+                let value = item.evaluate(spreadsheet).to_number(spreadsheet);
+                match compare(&operator, value, threshold) {
+                    Some(true) => matching.push(value),
+                    Some(false) => {}
+                    None => return Expression::Error(CellError::Value),
+                }
+            }
+            match function_name {
+                "sumif" => Expression::Number(matching.iter().sum()),
+                _ => Expression::Number(matching.len() as f64),
+            }
+        }
+        "repeat" => {
+            let text = params[0].evaluate(spreadsheet).to_raw_string();
+            let count = params[1].evaluate(spreadsheet).to_number(spreadsheet);
+            if count < 0.0 || count.fract() != 0.0 || text.len() as f64 * count > REPEAT_LIMIT as f64 {
+                return Expression::Error(CellError::Value);
+            }
+            Expression::String(text.repeat(count as usize))
+        }
+        // Pads with the first character of the third param up to a width in characters.
+        // Longer text is returned as is.
+        "pad_left" | "pad_right" => {
+            let text = params[0].evaluate(spreadsheet).to_raw_string();
+            let width = params[1].evaluate(spreadsheet).to_number(spreadsheet);
+            let Some(pad) = params[2].evaluate(spreadsheet).to_raw_string().chars().next() else {
+                return Expression::Error(CellError::Value);
+            };
+            if width * pad.len_utf8() as f64 > REPEAT_LIMIT as f64 {
+                return Expression::Error(CellError::Value);
+            }
+            let padding = pad.to_string().repeat((width as usize).saturating_sub(text.chars().count()));
+            match function_name {
+                "pad_left" => Expression::String(padding + &text),
+                _ => Expression::String(text + &padding),
+            }
+        }
+        "startswith" | "endswith" => {
+            let text = params[0].evaluate(spreadsheet).to_raw_string();
+            let pattern = params[1].evaluate(spreadsheet).to_raw_string();
+            let matches = match function_name {
+                "startswith" => text.starts_with(&pattern),
+                _ => text.ends_with(&pattern),
+            };
+            Expression::String(matches.to_string())
+        }
+        "lookup" => {
+            let n_rows = match params[1].evaluate(spreadsheet) {
+                Expression::Error(error) => return Expression::Error(error),
+                n_rows => n_rows.to_number(spreadsheet),
+            };
+            if !n_rows.is_finite() || n_rows.fract() != 0.0 {
+                return Expression::Error(CellError::Value);
+            }
+            if n_rows < 0.0 {
+                return Expression::Error(CellError::Ref);
+            }
+            Expression::LabelReference(LabelReference {
+                label: params[0].evaluate(spreadsheet).to_raw_string(),
+                n_rows: n_rows as usize,
+                n_columns: 0,
+            }).evaluate(spreadsheet)
+        }
+        "isblank" => Expression::String(params[0].evaluate(spreadsheet).is_blank().to_string()),
+        "isnumber" => Expression::String(params[0].evaluate(spreadsheet).is_number().to_string()),
+        "istext" => {
+            let value = params[0].evaluate(spreadsheet);
+            let is_text = matches!(value, Expression::String(_)) && !value.is_blank() && !value.is_number();
+            Expression::String(is_text.to_string())
+        }
+        "coalesce" => params
+            .iter()
+            .map(|param| param.evaluate(spreadsheet))
+            .find(|value| !value.is_blank())
+            .unwrap_or(Expression::String(String::new())),
+        "incfrom" => Expression::Number(params[0].evaluate(spreadsheet).to_number(spreadsheet)),
+        "row" => Expression::Number(*spreadsheet.evaluating_row.borrow() as f64),
+        "column" => Expression::Number(*spreadsheet.evaluating_column.borrow() as f64),
+        "address" => {
+            let row_number = params[0].evaluate(spreadsheet).to_number(spreadsheet);
+            let column_number = params[1].evaluate(spreadsheet).to_number(spreadsheet);
+            if row_number < 1.0 || column_number < 1.0 {
+                return Expression::Error(CellError::Value);
+            }
+            Expression::String(format!("{}{}", column_name_from_index(column_number as usize), row_number as usize))
+        }
+        "now" => Expression::String(format_timestamp((spreadsheet.clock)())),
+        "today" => Expression::String(format_day((spreadsheet.clock)())),
+        "dateadd" => {
+            let date = parse_date(&params[0].evaluate(spreadsheet).to_raw_string());
+            match date.and_then(|date| add_days(date, params[1].evaluate(spreadsheet).to_number(spreadsheet))) {
+                Some(date) => Expression::String(format_date(date)),
+                None => Expression::Error(CellError::Value),
+            }
+        }
+        "datediff" => {
+            let end = parse_date(&params[0].evaluate(spreadsheet).to_raw_string());
+            let start = parse_date(&params[1].evaluate(spreadsheet).to_raw_string());
+            match (end, start) {
+                (Some(end), Some(start)) => Expression::Number((end - start) as f64),
+                _ => Expression::Error(CellError::Value),
+            }
+        }
+        function_name => spreadsheet.fail(SpreadsheetError::UnknownFunction(function_name.to_string())),
+    }
+}
+
+/// The 1-based position of the cell `n_rows` below and `n_columns` right of the label at a 0-based
+/// position. `None` when the offsets, which `lookup()` computes at evaluation time, don't fit.
+pub(crate) fn offset_from_label((label_row_index, label_column_index): (usize, usize), n_rows: usize, n_columns: usize) -> Option<(usize, usize)> {
+    let row_number = label_row_index.checked_add(n_rows)?.checked_add(1)?;
+    let column_number = label_column_index.checked_add(n_columns)?.checked_add(1)?;
+    Some((row_number, column_number))
+}
+
+/// Returns the formula `^^` at the given position stands for, or `None` when there is nothing to
+/// copy: on the first row, or right under a label.
+fn copy_above_source(spreadsheet: &Spreadsheet, row_number: usize, column_number: usize) -> Option<Expression> {
+    let mut source_row_number = row_number;
+    loop {
+        source_row_number -= 1;
+        match spreadsheet.get_cell(source_row_number, column_number)? {
+            Expression::Label(_) => return None,
+            Expression::CopyAbove => {}
+            source_cell => return Some(source_cell.copied_down(row_number - source_row_number)),
+        }
+    }
+}
+
+/// Returns the 1-based row of the last cell in `column_number` that is neither empty nor a label.
+fn last_row_in_column(spreadsheet: &Spreadsheet, column_number: usize) -> Option<usize> {
+    spreadsheet
+        .rows
+        .iter()
+        .rposition(|row| !matches!(row.get(column_number - 1), None | Some(Expression::Empty | Expression::Label(_))))
+        .map(|row_index| row_index + 1)
+}
+
+/// Returns the column a lookup param refers to. `A^v` and `A1` both stand for column A.
+fn column_of(param: &Expression) -> Option<usize> {
+    match param {
+        Expression::ColumnReference(column_ref) => Some(column_ref.column),
+        Expression::CellReference(cell_ref) => Some(cell_ref.column),
+        _ => None,
+    }
+}
+
+/// Finds the first row whose cell in the key column equals `key`, and returns that row's cell in
+/// the value column. The cell being evaluated is skipped, so a lookup can live in either column.
+fn vlookup(spreadsheet: &Spreadsheet, key: &Expression, key_column: &Expression, value_column: &Expression) -> Expression {
+    let (Some(key_column), Some(value_column)) = (column_of(key_column), column_of(value_column)) else {
+        return spreadsheet.fail(SpreadsheetError::InvalidValue("vlookup() expects column references like A^v".to_string()));
+    };
+    let key = key.evaluate_recursively(spreadsheet).to_string();
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+
+    for row_number in 1..=spreadsheet.rows.len() {
+        if (row_number, key_column) == current || spreadsheet.get_cell(row_number, key_column).is_none() {
+            continue;
+        }
+        if spreadsheet.evaluate_reference(row_number, key_column).to_string() == key {
+            return spreadsheet.evaluate_reference(row_number, value_column);
+        }
+    }
+    Expression::Error(CellError::NA)
+}
+
+/// Compares `value` with the first of each `(case, result)` pair and evaluates the result of the
+/// first case with the same text. An odd param left at the end is the default.
+fn switch(spreadsheet: &Spreadsheet, value: &Expression, cases: &[Expression]) -> Expression {
+    let value = value.evaluate_recursively(spreadsheet);
+    if let Expression::Error(error) = value {
+        return Expression::Error(error);
+    }
+    let value = value.to_string();
+
+    let mut pairs = cases.chunks_exact(2);
+    for pair in pairs.by_ref() {
+        if pair[0].evaluate_recursively(spreadsheet).to_string() == value {
+            return pair[1].evaluate(spreadsheet);
+        }
+    }
+    match pairs.remainder() {
+        [default] => default.evaluate(spreadsheet),
+        _ => Expression::Error(CellError::NA),
+    }
+}
+
+/// Returns the 1-based position of `key` in a list, or its row number in a column like `A^v`, so
+/// that `index(B^v, match(key, A^v))` reads the same row of another column.
+fn match_position(spreadsheet: &Spreadsheet, key: &Expression, items: &Expression) -> Expression {
+    let key = key.evaluate_recursively(spreadsheet).to_string();
+    let position = match items {
+        Expression::ColumnReference(column_ref) => {
+            let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+            (1..=spreadsheet.rows.len()).find(|&row_number| {
+                (row_number, column_ref.column) != current
+                    && spreadsheet.get_cell(row_number, column_ref.column).is_some()
+                    && spreadsheet.evaluate_reference(row_number, column_ref.column).to_string() == key
+            })
+        }
+        items => list_items(items.evaluate_recursively(spreadsheet))
+            .iter()
+            .position(|item| item.to_string() == key)
+            .map(|index| index + 1),
+    };
+    match position {
+        Some(position) => Expression::Number(position as f64),
+        None => Expression::Error(CellError::NA),
+    }
+}
+
+/// Returns the item at a 1-based position of a list, or the cell at that row of a column like `A^v`.
+fn index(spreadsheet: &Spreadsheet, items: &Expression, position: &Expression) -> Expression {
+    let position = position.evaluate_recursively(spreadsheet);
+    if let Expression::Error(error) = position {
+        return Expression::Error(error);
+    }
+    let position = position.to_number(spreadsheet);
+    if position < 1.0 || position.fract() != 0.0 {
+        return Expression::Error(CellError::Ref);
+    }
+    match items {
+        Expression::ColumnReference(column_ref) => spreadsheet.evaluate_reference(position as usize, column_ref.column),
+        items => list_items(items.evaluate_recursively(spreadsheet))
+            .get(position as usize - 1)
+            .map_or(Expression::Error(CellError::Ref), |item| item.evaluate_recursively(spreadsheet)),
+    }
+}
+
+/// Averages values weighted by the weights at the same positions. Both can be lists or spreads,
+/// which is why the function takes its params unevaluated: expanded, they'd run together.
+fn weighted_average(spreadsheet: &Spreadsheet, values: &Expression, weights: &Expression) -> Expression {
+    let to_numbers = |param: &Expression| {
+        list_items(param.evaluate_recursively(spreadsheet))
+            .iter()
+            .map(|item| match item.evaluate(spreadsheet) {
+                Expression::Error(error) => Err(error),
+                item => Ok(item.to_number(spreadsheet)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let (values, weights) = match (to_numbers(values), to_numbers(weights)) {
+        (Ok(values), Ok(weights)) => (values, weights),
+        (Err(error), _) | (_, Err(error)) => return Expression::Error(error),
+    };
+    if values.len() != weights.len() {
+        return spreadsheet.fail(SpreadsheetError::InvalidValue(format!(
+            "weighted_average() expects as many weights as values, got {} values and {} weights",
+            values.len(),
+            weights.len(),
+        )));
+    }
+    let total_weight = weights.iter().sum::<f64>();
+    if total_weight == 0.0 {
+        return spreadsheet.fail(SpreadsheetError::DivisionByZero);
+    }
+    Expression::Number(values.iter().zip(&weights).map(|(value, weight)| value * weight).sum::<f64>() / total_weight)
+}
+
+/// Joins the values of every cell in a column, leaving out labels and blanks.
+fn concat_column(spreadsheet: &Spreadsheet, column: &Expression, separator: Option<&Expression>) -> Expression {
+    let Some(column_number) = column_of(column) else {
+        return spreadsheet.fail(SpreadsheetError::InvalidValue("concat_column() expects a column reference like A^v".to_string()));
+    };
+    let separator = separator.map_or(String::new(), |separator| separator.evaluate_recursively(spreadsheet).to_raw_string());
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+
+    let values = (1..=spreadsheet.rows.len())
+        .filter(|&row_number| (row_number, column_number) != current)
+        .filter(|&row_number| !matches!(spreadsheet.get_cell(row_number, column_number), None | Some(Expression::Empty | Expression::Label(_))))
+        .map(|row_number| spreadsheet.evaluate_reference(row_number, column_number).to_string())
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    Expression::String(values.join(&separator))
+}
+
+/// The running total of a column down to the row being evaluated, so `=cumsum(A^v)` copied
+/// down next to a column of amounts gives its cumulative sums. Labels and blanks are skipped.
+fn cumsum(spreadsheet: &Spreadsheet, column: &Expression) -> Expression {
+    let Some(column_number) = column_of(column) else {
+        return spreadsheet.fail(SpreadsheetError::InvalidValue("cumsum() expects a column reference like A^v".to_string()));
+    };
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+
+    let mut total = 0.0;
+    for row_number in 1..=current.0 {
+        if (row_number, column_number) == current
+            || matches!(spreadsheet.get_cell(row_number, column_number), None | Some(Expression::Empty | Expression::Label(_))) {
+            continue;
+        }
+        total += spreadsheet.evaluate_reference(row_number, column_number).to_number(spreadsheet);
+    }
+    Expression::Number(total)
+}
+
+/// Counts the cells of a column like `A^v`, or of rows like `2:3`, that are empty or evaluate to
+/// empty text. The cell being evaluated doesn't count.
+fn countblank(spreadsheet: &Spreadsheet, range: &Expression) -> Expression {
+    let positions = match (range, column_of(range)) {
+        (Expression::RowReference(row_ref), _) if row_ref.first == 0 => {
+            return spreadsheet.fail(SpreadsheetError::InvalidReference(row_ref.name.clone()));
+        }
+        (Expression::RowReference(row_ref), _) => (row_ref.first..=row_ref.last.min(spreadsheet.rows.len()))
+            .flat_map(|row_number| (1..=spreadsheet.rows[row_number - 1].len()).map(move |column_number| (row_number, column_number)))
+            .collect::<Vec<_>>(),
+        (_, Some(column_number)) => (1..=spreadsheet.rows.len()).map(|row_number| (row_number, column_number)).collect(),
+        _ => return spreadsheet.fail(SpreadsheetError::InvalidValue("countblank() expects a column like A^v or rows like 2:2".to_string())),
+    };
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+
+    let blanks = positions
+        .into_iter()
+        .filter(|&position| position != current)
+        .filter(|&(row_number, column_number)| match spreadsheet.get_cell(row_number, column_number) {
+            None | Some(Expression::Label(_)) => false,
+            Some(Expression::Empty) => true,
+            Some(_) => spreadsheet.evaluate_reference(row_number, column_number).is_blank(),
+        })
+        .count();
+    Expression::Number(blanks as f64)
+}
+
+/// The items of a list or spread. Any other value is a list of its own.
+fn list_items(value: Expression) -> Vec<Expression> {
+    match value {
+        Expression::List { expressions } | Expression::Spread(expressions) => expressions,
+        value => vec![value],
+    }
+}
+
+/// Finds the first column whose cell in the key row equals `key`, and returns the cell `offset`
+/// rows below it. The key row is either a row number or a cell reference like `A1`.
+fn hlookup(spreadsheet: &Spreadsheet, key: &Expression, key_row: &Expression, offset: &Expression) -> Expression {
+    let key_row = match key_row {
+        Expression::CellReference(cell_ref) => cell_ref.row as f64,
+        key_row => key_row.evaluate_recursively(spreadsheet).to_number(spreadsheet),
+    };
+    let offset = offset.evaluate_recursively(spreadsheet).to_number(spreadsheet);
+    if key_row < 1.0 || key_row.fract() != 0.0 || offset.fract() != 0.0 {
+        return Expression::Error(CellError::Value);
+    }
+    let (key_row, value_row) = (key_row as usize, key_row + offset);
+    let key = key.evaluate_recursively(spreadsheet).to_string();
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+
+    let columns = spreadsheet.rows.get(key_row - 1).map_or(0, Vec::len);
+    for column_number in 1..=columns {
+        if (key_row, column_number) == current {
+            continue;
+        }
+        if spreadsheet.evaluate_reference(key_row, column_number).to_string() == key {
+            if value_row < 1.0 {
+                return Expression::Error(CellError::Ref);
+            }
+            return spreadsheet.evaluate_reference(value_row as usize, column_number);
+        }
+    }
+    Expression::Error(CellError::NA)
+}
+
+/// Returns the positions of the cells in the given rows that hold something other than a label,
+/// leaving out the cell being evaluated.
+fn populated_cells_in_rows(spreadsheet: &Spreadsheet, row_ref: &RowReference) -> Vec<(usize, usize)> {
+    let current = (*spreadsheet.evaluating_row.borrow(), *spreadsheet.evaluating_column.borrow());
+    let mut positions = vec![];
+    // `0:N` evaluates to `#REF!`, but dependency collection still reaches here.
+    for row_number in row_ref.first.max(1)..=row_ref.last.min(spreadsheet.rows.len()) {
+        for (column_index, cell) in spreadsheet.rows[row_number - 1].iter().enumerate() {
+            let position = (row_number, column_index + 1);
+            if position != current && !matches!(cell, Expression::Empty | Expression::Label(_)) {
+                positions.push(position);
+            }
+        }
+    }
+    positions
+}
+
+/// Whether a literal cell can be written as it is, without being read back as a label, formula,
+/// percentage or several cells.
+fn is_plain_text(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(['=', '!', '"', '#'])
+        && !text.contains(|c: char| c == '|' || c.is_whitespace())
+        && parse_cell_from_str(text) == Some(Expression::String(text.to_string()))
+}
+
+/// Writes text as a double-quoted string, escaping what the parser unescapes.
+fn quoted(text: &str) -> String {
+    let mut output = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// Rounds away the binary floating point noise of arithmetic like `0.1 + 0.2`, by keeping the
+/// 15 significant digits an `f64` can represent exactly.
+fn round_to_precision(number: f64) -> f64 {
+    format!("{:.14e}", number).parse().unwrap_or(number)
+}
+
+/// Compares two numbers with a named operator: `gt`, `gte` (or `bte`), `lt`, `lte`, `eq` or `ne`.
+/// Returns `None` for an unknown operator.
+fn compare(operator: &str, left: f64, right: f64) -> Option<bool> {
+    Some(match operator {
+        "gt" => left > right,
+        "gte" | "bte" => left >= right,
+        "lt" => left < right,
+        "lte" => left <= right,
+        "eq" => left == right,
+        "ne" => left != right,
+        _ => return None,
+    })
+}
+
+/// Whether a built-in function evaluates its params itself. Lookups search whole columns and
+/// rows, so their references mustn't be evaluated to a single cell first, and `if` only
+/// evaluates the branch it picks.
+fn takes_unevaluated_params(function_name: &str) -> bool {
+    matches!(function_name, "if" | "switch" | "vlookup" | "hlookup" | "match" | "index" | "concat_column" | "cumsum" | "countblank" | "weighted_average")
+}
+
+/// Returns the minimum and maximum number of params a built-in function accepts, after spreads
+/// have been expanded into separate params. Functions that take unevaluated params are checked
+/// before, so a spread counts as one param for them.
+fn arity(function_name: &str) -> Option<(usize, usize)> {
+    Some(match function_name {
+        "now" | "today" | "row" | "column" => (0, 0),
+        "clamp" | "pad_left" | "pad_right" => (3, 3),
+        "upper" | "lower" | "abs" | "sign" | "sqrt" | "exp" | "ln" | "isblank" | "isnumber" | "istext" | "cumsum" | "countblank" => (1, 1),
+        "text" | "incfrom" | "concat_column" => (1, 2),
+        "gte" | "bte" | "lte" | "repeat" | "startswith" | "endswith" | "split" | "split_trim" | "split_any" | "lookup" | "address" | "dateadd" | "datediff" | "match" | "index" | "weighted_average" => (2, 2),
+        "approx" | "if" => (2, 3),
+        "vlookup" | "hlookup" => (3, 3),
+        "format_number" => (4, 4),
+        "map" | "contains" | "textjoin" => (2, usize::MAX),
+        "reduce" | "sumif" | "countif" | "switch" => (3, usize::MAX),
+        "sum" | "min" | "max" | "variance" | "stdev" | "varp" | "stdevp" | "concat" | "concat_raw" | "coalesce" | "spread" => (0, usize::MAX),
+        _ => return None,
+    })
+}
+
+/// Fails with an error naming the function when it's called with the wrong number of params.
+fn check_arity(function_name: &str, params: &[Expression]) -> Result<(), SpreadsheetError> {
+    let Some((min, max)) = arity(function_name) else { return Ok(()) };
+    if (min..=max).contains(&params.len()) {
+        return Ok(());
+    }
+
+    let expected = match (min, max) {
+        (min, max) if min == max => min.to_string(),
+        (min, usize::MAX) => format!("at least {}", min),
+        (min, max) => format!("{} to {}", min, max),
+    };
+    Err(SpreadsheetError::WrongArity { function: function_name.to_string(), expected, got: params.len() })
+}
+
+/// Turns the tokens of a split string into a list, parsing each one like a cell.
+fn list_of_tokens<'a>(tokens: impl Iterator<Item = &'a str>) -> Expression {
+    let expressions = tokens
+        .map(|token| parse_cell_from_str(token).unwrap_or(Expression::String(token.to_string())))
+        .collect();
+    Expression::List { expressions }
+}
+
+/// Evaluates the operands of an arithmetic operator. Errors are contagious, so the first error
+/// value among them is what the whole operation evaluates to.
+fn operands(spreadsheet: &Spreadsheet, args: &[Expression]) -> Result<Vec<f64>, CellError> {
+    args.iter()
+        .map(|arg| match arg.evaluate(spreadsheet) {
+            Expression::Error(error) => Err(error),
+            value => Ok(value.to_number(spreadsheet)),
+        })
+        .collect()
+}
+
+/// Reads params as numbers for the statistical functions. Scalars, lists and spreads all count as
+/// their items, and blanks are left out. Fails with the first error value among them.
+fn numbers_of(spreadsheet: &Spreadsheet, params: &[Expression]) -> Result<Vec<f64>, CellError> {
+    let mut numbers = vec![];
+    for item in flatten_lists(params) {
+        match item.evaluate(spreadsheet) {
+            Expression::Error(error) => return Err(error),
+            value if value.is_blank() => {}
+            value => numbers.push(value.to_number(spreadsheet)),
         }
     }
+    Ok(numbers)
+}
+
+/// Expands `List` values in place, so functions can treat lists and separate params alike.
+fn flatten_lists(items: &[Expression]) -> Vec<Expression> {
+    items
+        .iter()
+        .flat_map(|item| match item {
+            Expression::List { expressions } => expressions.clone(),
+            item => vec![item.clone()],
+        })
+        .collect()
+}
+
+/// Formats a number using a spreadsheet-like format spec: `0` for an integer, `0.00` for a fixed
+/// number of decimals, and a trailing `%` to render the value as a percentage (`0%`, `0.0%`).
+/// Returns `None` for any other format.
+fn format_number(number: f64, format: &str) -> Option<String> {
+    let (format, number, suffix) = match format.strip_suffix('%') {
+        Some(format) => (format, number * 100.0, "%"),
+        None => (format, number, ""),
+    };
+
+    let decimals = match format.split_once('.') {
+        Some(("0", decimals)) if decimals.chars().all(|c| c == '0') => decimals.len(),
+        None if format == "0" => 0,
+        _ => return None,
+    };
+
+    Some(format!("{:.decimals$}{}", number, suffix, decimals = decimals))
 }
 
 impl std::fmt::Display for Expression {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let number = match self {
+            Expression::Number(number) if !number.is_finite() => return CellError::Num.fmt(fmt),
             Expression::Number(number) => *number,
+            Expression::Percentage(percent) => return fmt.write_str(&format!("{}%", percent)),
+            Expression::Error(error) => return error.fmt(fmt),
+            // Lists render like the text they're usually split from.
+            Expression::List { expressions } | Expression::Spread(expressions) => {
+                let items = expressions.iter().map(Expression::to_string).collect::<Vec<_>>();
+                return fmt.write_str(&items.join(","));
+            }
+            // Text like "inf" or "NaN" parses as a float, but it's only meant as a number when finite.
             Expression::String(string) => match string.parse::<f64>() {
-                Ok(number) => number,
-                Err(_) => return fmt.write_str(string),
+                Ok(number) if number.is_finite() => number,
+                _ => return fmt.write_str(string),
             },
-            _ => return fmt.write_str("unexpected error")
+            Expression::Empty => return Ok(()),
+            Expression::Label(name) => return fmt.write_str(name),
+            // Anything else hasn't been evaluated yet, so it renders roughly the way it was written.
+            Expression::CellReference(cell_ref) => return fmt.write_str(&cell_ref.name),
+            Expression::LabelReference(label_ref) => return write!(fmt, "@{}<{},{}>", label_ref.label, label_ref.n_rows, label_ref.n_columns),
+            Expression::ColumnReference(column_ref) => return write!(fmt, "{}^v", column_ref.name),
+            Expression::RowReference(row_ref) => return fmt.write_str(&row_ref.name),
+            Expression::CopyAbove => return fmt.write_str("^^"),
+            Expression::CopyEvaluated(column_ref) => return write!(fmt, "{}^", column_ref.name),
+            Expression::Function { name, params } => {
+                let params = params.iter().map(Expression::to_string).collect::<Vec<_>>();
+                return write!(fmt, "{}({})", name, params.join(", "));
+            }
+            Expression::Plus { args } => return write_operation(fmt, "+", args),
+            Expression::Minus { args } => return write_operation(fmt, "-", args),
+            Expression::Multiply { args } => return write_operation(fmt, "*", args),
+            Expression::Divide { args } => return write_operation(fmt, "/", args),
+            Expression::Power { args } => return write_operation(fmt, "^", args),
+            Expression::Negate(expr) => return write!(fmt, "-{}", expr),
         };
 
-        if number.fract() == 0.0 {
-            fmt.write_str(&format!("{}", number))
+        let rounded = (round_to_precision(number) * 100.0).round() / 100.0;
+        if rounded.fract() == 0.0 {
+            fmt.write_str(&format!("{}", rounded + 0.0))
         } else {
             fmt.write_str(&format!("{:.2}", number))
         }
     }
 }
+
+fn write_operation(fmt: &mut std::fmt::Formatter<'_>, operator: &str, args: &[Expression]) -> std::fmt::Result {
+    let args = args.iter().map(Expression::to_string).collect::<Vec<_>>();
+    write!(fmt, "({})", args.join(operator))
+}
+
+/// Formats a number with a fixed number of decimals, and the given separators between the
+/// integer and fractional part and between groups of thousands, e.g. `1.234.567,89`.
+fn format_with_separators(number: f64, decimals: usize, decimal_separator: &str, thousands_separator: &str) -> String {
+    let formatted = format!("{:.decimals$}", number.abs(), decimals = decimals);
+    let (integer, fraction) = match formatted.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (formatted.as_str(), None),
+    };
+
+    // Rounding can turn a small negative number into zero, which shouldn't keep its sign.
+    let mut output = String::new();
+    if number < 0.0 && formatted.bytes().any(|digit| digit.is_ascii_digit() && digit != b'0') {
+        output.push('-');
+    }
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            output.push_str(thousands_separator);
+        }
+        output.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        output.push_str(decimal_separator);
+        output.push_str(fraction);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::parser::parse_cell_from_str;
+    use crate::{CellError, Spreadsheet, SpreadsheetError};
+    use super::Expression;
+
+    fn evaluate(formula: &str) -> String {
+        Spreadsheet::from_str(formula).evaluate().spreadsheet[0][0].clone()
+    }
+
+    fn error(formula: &str) -> SpreadsheetError {
+        Spreadsheet::from_str(formula).try_evaluate().err().unwrap()
+    }
+
+    #[test]
+    fn test_text_with_format() {
+        assert_eq!(evaluate(r#"=text(0.1234, "0.00")"#), "0.12");
+        assert_eq!(evaluate(r#"=text(0.5, "0%")"#), "50%");
+        assert_eq!(evaluate(r#"=text(0.1234, "0.0%")"#), "12.3%");
+        assert_eq!(evaluate(r#"=text(41.6, "0")"#), "42");
+        assert_eq!(evaluate("=text(0.5)"), "0.50");
+    }
+
+    #[test]
+    fn test_percentage_arithmetic() {
+        let evaluated = Spreadsheet::from_str("9%|=A1*100|=A1").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["9%", "9", "9%"]);
+        assert_eq!(evaluate("=50%*4"), "2");
+    }
+
+    #[test]
+    fn test_negated_percentage() {
+        let evaluated = Spreadsheet::from_str("9%|=-A1|=--A1|=-A1*100").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["9%", "-9%", "9%", "-9"]);
+        assert_eq!(evaluate("=-9%"), "-9%");
+        assert_eq!(evaluate("=-(50%)*4"), "-2");
+    }
+
+    #[test]
+    fn test_now_and_today() {
+        let evaluated = Spreadsheet::from_str("=now()|=today()")
+            .with_clock(|| UNIX_EPOCH + Duration::from_secs(1_645_364_700))
+            .evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["2022-02-20T13:45:00Z", "2022-02-20"]);
+    }
+
+    #[test]
+    fn test_date_arithmetic() {
+        assert_eq!(evaluate(r#"=dateadd("2022-02-20", 5)"#), "2022-02-25");
+        assert_eq!(evaluate(r#"=dateadd("2022-02-27", 2)"#), "2022-03-01");
+        assert_eq!(evaluate(r#"=datediff("2022-02-22","2022-02-20")"#), "2");
+        assert_eq!(evaluate(r#"=datediff("2022-02-20","2022-02-22")"#), "-2");
+        assert_eq!(evaluate(r#"=dateadd("2022-02-30", 1)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=dateadd("2022-01-01", 1e30)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=dateadd("2022-01-01", 1.5)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=dateadd("2022-01-01", 0-1e30)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=datediff("soon","2022-02-20")"#), "#VALUE!");
+    }
+
+    #[test]
+    fn test_approx() {
+        assert_eq!(evaluate("=approx(0.3, 0.3000000000001)"), "true");
+        assert_eq!(evaluate("=approx(0.3, 0.30001)"), "false");
+        assert_eq!(evaluate("=approx(1, 1.005, 0.01)"), "true");
+        assert_eq!(evaluate("=approx(1, 1.05, 0.01)"), "false");
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        let evaluated = Spreadsheet::from_str("4|=-A1|=3*-2|=-(A1+1)").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["4", "-4", "-6", "-5"]);
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        assert_eq!(evaluate("=1+2*3"), "7");
+        assert_eq!(evaluate("=8-2-1"), "5");
+        assert_eq!(evaluate("=8/2/2"), "2");
+        assert_eq!(evaluate("=2*3-4/2+1"), "5");
+        assert_eq!(evaluate("=(1+2)*3"), "9");
+        assert_eq!(evaluate("=2*3^2"), "18");
+        assert_eq!(evaluate("=2^3^2"), "512");
+        assert_eq!(evaluate("=(2^3)^2"), "64");
+    }
+
+    #[test]
+    fn test_unknown_label() {
+        let evaluated = Spreadsheet::from_str("!a|error\n1|=@b<1>\n=@a<1>|=B1").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[1][1], "#NAME?");
+        assert_eq!(evaluated.spreadsheet[2], vec!["1", "error"]);
+    }
+
+    #[test]
+    fn test_copy_above_without_row_above() {
+        let evaluated = Spreadsheet::from_str("=^^|1|=B1*2\n!a|2|=^^\n=^^|3|=B^").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["#REF!", "1", "2"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["a", "2", "4"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["#REF!", "3", "2"]);
+    }
+
+    #[test]
+    fn test_copy_above_keeps_numbering() {
+        let evaluated = Spreadsheet::from_str("=incFrom(1)|=concat(\"t_\", text(incFrom(10)))\n=^^|=^^\n=^^|=A^+incFrom(100)").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["1", "t_10"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["2", "t_11"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["3", "102"]);
+    }
+
+    #[test]
+    fn test_inc_from_with_step() {
+        let evaluated = Spreadsheet::from_str("=incFrom(100, 25)|=incFrom(1, 0-1)\n=^^|=^^\n=^^|=^^").evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![vec!["100", "1"], vec!["125", "0"], vec!["150", "-1"]]);
+    }
+
+    #[test]
+    fn test_row_and_column() {
+        let evaluated = Spreadsheet::from_str("=row()|=column()\n=concat(\"id_\", text(row()))|=row()*10+column()\n=A1|=^^").evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![vec!["1", "2"], vec!["id_2", "22"], vec!["1", "32"]]);
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(evaluate("=address(2, 3)"), "C2");
+        assert_eq!(evaluate("=address(1, 26)"), "Z1");
+        assert_eq!(evaluate("=address(10, 27)"), "AA10");
+        assert_eq!(evaluate("=address(7, 703)"), "AAA7");
+        assert_eq!(evaluate("=address(row(), column()+1)"), "B1");
+        assert_eq!(evaluate("=address(0, 1)"), "#VALUE!");
+    }
+
+    #[test]
+    fn test_out_of_range_references() {
+        let evaluated = Spreadsheet::from_str("!a|=Z99\n1|=A0\n=A^|=@a<5>").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["a", "#REF!"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["1", "#REF!"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["1", "#REF!"]);
+        assert_eq!(evaluate("=A^"), "#REF!");
+    }
+
+    #[test]
+    fn test_absolute_references() {
+        let evaluated = Spreadsheet::from_str("1|10|=A1+$B$1|=$A1*B$1\n2|20|=^^|=^^\n3|30|=^^|=^^").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["1", "10", "11", "10"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["2", "20", "12", "20"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["3", "30", "13", "30"]);
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eq!(evaluate(r#"=concat(spread(map(split("a,b,c", ","), "upper")))"#), "ABC");
+        assert_eq!(evaluate(r#"=concat(spread(map(spread(split("A,b", ",")), "lower")))"#), "ab");
+        assert_eq!(evaluate(r#"=sum(spread(map(split("-1,2,-3", ","), "abs")))"#), "6");
+    }
+
+    #[test]
+    fn test_reduce() {
+        assert_eq!(evaluate(r#"=reduce(split("1,2,3", ","), "+", 10)"#), "16");
+        assert_eq!(evaluate(r#"=reduce(spread(split("1,2,3", ",")), "sum", 10)"#), "16");
+        assert_eq!(evaluate(r#"=reduce(split("2,3", ","), "*", 10)"#), "60");
+        assert_eq!(evaluate(r#"=reduce(split("a,b", ","), "concat", "x")"#), "xab");
+    }
+
+    #[test]
+    fn test_contains() {
+        assert_eq!(evaluate(r#"=contains(split("a,b,c", ","), "b")"#), "true");
+        assert_eq!(evaluate(r#"=contains(spread(split("a,b,c", ",")), "d")"#), "false");
+        assert_eq!(evaluate(r#"=contains(split("ab,c", ","), "b")"#), "false");
+        assert_eq!(evaluate(r#"=contains("t_12", "_1")"#), "true");
+        assert_eq!(evaluate(r#"=contains("t_12", "3")"#), "false");
+    }
+
+    #[test]
+    fn test_type_predicates() {
+        let evaluated = Spreadsheet::from_str("|12.5|abc\n=isblank(A1)|=isblank(B1)|=isblank(C1)\n=isnumber(A1)|=isnumber(B1)|=isnumber(C1)\n=istext(A1)|=istext(B1)|=istext(C1)").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[1], vec!["true", "false", "false"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["false", "true", "false"]);
+        assert_eq!(evaluated.spreadsheet[3], vec!["false", "false", "true"]);
+
+        // Rust reads these as floats, but the sheet shows them as text.
+        let evaluated = Spreadsheet::from_str("inf|-inf|NaN\n=isnumber(A1)|=isnumber(B1)|=isnumber(C1)\n=istext(A1)|=istext(B1)|=istext(C1)\n=A1+1|=B1+1|=C1+1").evaluate();
+        assert_eq!(evaluated.spreadsheet[1], vec!["false", "false", "false"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["true", "true", "true"]);
+        assert_eq!(evaluated.spreadsheet[3], vec!["1", "1", "1"]);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let evaluated = Spreadsheet::from_str(r#"|=coalesce(A1, "default")|=coalesce(A1, B1, "other")|=coalesce(A1, A1)"#).evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["", "default", "default", ""]);
+    }
+
+    #[test]
+    fn test_spread_scalars() {
+        assert_eq!(evaluate("=sum(spread(5), 1)"), "6");
+        assert_eq!(evaluate(r#"=concat(spread("a"), "b")"#), "ab");
+        assert_eq!(evaluate(r#"=sum(spread(spread(split("1,2", ","))))"#), "3");
+    }
+
+    #[test]
+    fn test_arity_of_text() {
+        assert_eq!(evaluate("=text()"), "#VALUE!");
+        assert_eq!(error("=text()").to_string(), "text() expects 1 to 2 params, got 0");
+    }
+
+    #[test]
+    fn test_arity_of_split() {
+        assert_eq!(evaluate(r#"=split("a")"#), "#VALUE!");
+        assert_eq!(error(r#"=split("a")"#).to_string(), "split() expects 2 params, got 1");
+    }
+
+    #[test]
+    fn test_arity_of_reduce() {
+        assert_eq!(evaluate(r#"=reduce(split("1,2", ","), "+")"#), "#VALUE!");
+        assert_eq!(error(r#"=reduce(split("1,2", ","), "+")"#).to_string(), "reduce() expects at least 3 params, got 2");
+    }
+
+    #[test]
+    fn test_number_formatting() {
+        assert_eq!(evaluate("=0.1+0.2"), "0.30");
+        assert_eq!(evaluate("=3*1.1"), "3.30");
+        assert_eq!(evaluate("=1.005*1000"), "1005");
+        assert_eq!(evaluate("=0-0.001"), "0");
+        assert_eq!(evaluate(r#"=contains(0.1+0.2, "4")"#), "false");
+        assert_eq!(evaluate(r#"=sum(spread(split("4000000000000,5000000000000,85", ",")))"#), "9000000000085");
+    }
+
+    #[test]
+    fn test_sumif_countif() {
+        assert_eq!(evaluate(r#"=sumif(split("1,2,3,4", ","), "gt", 2)"#), "7");
+        assert_eq!(evaluate(r#"=sumif(split("1,2,3,4", ","), "lte", 2)"#), "3");
+        assert_eq!(evaluate(r#"=sumif(spread(split("1,2,3,4", ",")), "eq", 3)"#), "3");
+        assert_eq!(evaluate(r#"=sumif(split("1,2,3,4", ","), "gt", 10)"#), "0");
+        assert_eq!(evaluate(r#"=countif(split("1,2,3,4", ","), "gte", 2)"#), "3");
+        assert_eq!(evaluate(r#"=countif(5, 6, 7, "ne", 6)"#), "2");
+        assert_eq!(evaluate(r#"=sumif(split("1,2", ","), "between", 1)"#), "#VALUE!");
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(evaluate("=clamp(0-5, 0, 10)"), "0");
+        assert_eq!(evaluate("=clamp(2.5, 0, 10)"), "2.50");
+        assert_eq!(evaluate("=clamp(50, 0, 10)"), "10");
+        assert_eq!(evaluate("=clamp(50, 10, 0)"), "10");
+        assert_eq!(evaluate("=clamp(5, 5, 5)"), "5");
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(evaluate("=sign(3-10)"), "-1");
+        assert_eq!(evaluate("=sign(0)"), "0");
+        assert_eq!(evaluate("=sign(0-0)"), "0");
+        assert_eq!(evaluate("=sign(0.25)"), "1");
+    }
+
+    #[test]
+    fn test_sqrt_exp_ln() {
+        assert_eq!(evaluate("=sqrt(16)"), "4");
+        assert_eq!(evaluate("=sqrt(2)"), "1.41");
+        assert_eq!(evaluate("=sqrt(0)"), "0");
+        assert_eq!(evaluate("=sqrt(0-4)"), "#NUM!");
+        assert_eq!(evaluate("=exp(0)"), "1");
+        assert_eq!(evaluate("=exp(1)"), "2.72");
+        assert_eq!(evaluate("=ln(exp(3))"), "3");
+        assert_eq!(evaluate("=ln(0)"), "#NUM!");
+        assert_eq!(evaluate("=ln(0-1)"), "#NUM!");
+    }
+
+    #[test]
+    fn test_non_finite_numbers() {
+        assert_eq!(evaluate("=exp(1000)"), "#NUM!");
+        assert_eq!(evaluate("=0-exp(1000)"), "#NUM!");
+        assert_eq!(evaluate("=exp(1000)-exp(1000)"), "#NUM!");
+        assert_eq!(evaluate("=text(exp(1000)*0)"), "#NUM!");
+        assert_eq!(evaluate("NaN"), "NaN");
+        assert_eq!(Expression::Number(f64::INFINITY).to_string(), "#NUM!");
+        assert_eq!(Expression::Number(f64::NAN).to_string(), "#NUM!");
+    }
+
+    #[test]
+    fn test_textjoin() {
+        assert_eq!(evaluate(r#"=textjoin("-", "true", spread(split("a,,c", ",")))"#), "a-c");
+        assert_eq!(evaluate(r#"=textjoin("-", "false", spread(split("a,,c", ",")))"#), "a--c");
+        assert_eq!(evaluate(r#"=textjoin(", ", 1, split("x,y", ","), 3)"#), "x, y, 3");
+        assert_eq!(evaluate(r#"=textjoin("-", 1)"#), "");
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(evaluate(r#"=format_number(1234567.891, 2, ".", ",")"#), "1,234,567.89");
+        assert_eq!(evaluate(r#"=format_number(1234567.891, 2, ",", ".")"#), "1.234.567,89");
+        assert_eq!(evaluate(r#"=format_number(0-1234.6, 0, ",", " ")"#), "-1 235");
+        assert_eq!(evaluate(r#"=format_number(1, 20, ",", ".")"#), format!("1,{}", "0".repeat(20)));
+        assert_eq!(evaluate(r#"=format_number(1, 21, ".", ",")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=format_number(1, 1e19, ".", ",")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=format_number(1, 0-1, ".", ",")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=format_number(999.999, 2, ",", ".")"#), "1.000,00");
+        assert_eq!(evaluate(r#"=format_number(0-0.001, 2, ",", ".")"#), "0,00");
+    }
+
+    #[test]
+    fn test_repeat() {
+        assert_eq!(evaluate(r#"=repeat("ab", 3)"#), "ababab");
+        assert_eq!(evaluate(r#"=repeat("ab", 0)"#), "");
+        assert_eq!(evaluate(r#"=repeat("ab", 0-1)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=repeat("ab", 1.5)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=repeat("ab", 1000000000000)"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=repeat("", 1000000000000)"#), "");
+    }
+
+    #[test]
+    fn test_pad_left_pad_right() {
+        let evaluated = Spreadsheet::from_str("=1|=2|\n=pad_left(text(row()), 4, \"0\")|=pad_right(\"ab\", 4, \"*\")|=pad_left(\"é\", 3, \"·\")").evaluate();
+        assert_eq!(evaluated.with_verbatim_strings().spreadsheet[1], vec!["0002", "ab**", "··é"]);
+
+        assert_eq!(evaluate(r#"=pad_left("toolong", 3, "0")"#), "toolong");
+        assert_eq!(evaluate(r#"=pad_right("toolong", 3, "0")"#), "toolong");
+        assert_eq!(evaluate(r#"=pad_right("x", 3, "")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=pad_left("a", 1e19, "0")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=pad_right("a", 1e18, "0")"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=pad_left("a", 600000, "·")"#), "#VALUE!");
+    }
+
+    #[test]
+    fn test_startswith_endswith() {
+        assert_eq!(evaluate(r#"=startswith("t_1", "t_")"#), "true");
+        assert_eq!(evaluate(r#"=startswith("t_1", "_1")"#), "false");
+        assert_eq!(evaluate(r#"=endswith("t_1", "_1")"#), "true");
+        assert_eq!(evaluate(r#"=endswith(concat("id_", text(row())), "1")"#), "true");
+        assert_eq!(evaluate(r#"=startswith("abc", "")"#), "true");
+    }
+
+    #[test]
+    fn test_split_trim() {
+        assert_eq!(evaluate(r#"=textjoin("|", 0, split("a, b ,c", ","))"#), "a| b |c");
+        assert_eq!(evaluate(r#"=textjoin("|", 0, split_trim("a, b ,c", ","))"#), "a|b|c");
+        assert_eq!(evaluate(r#"=contains(split_trim("x,  y", ","), "y")"#), "true");
+        assert_eq!(evaluate(r#"=sum(spread(split_trim(" 1 ; 2 ", ";")))"#), "3");
+    }
+
+    #[test]
+    fn test_split_any() {
+        assert_eq!(evaluate(r#"=textjoin("|", 0, split_any("a, b;c", ",; "))"#), "a|b|c");
+        assert_eq!(evaluate(r#"=sum(spread(split_any("1;2,3 4", ",; ")))"#), "10");
+        assert_eq!(evaluate(r#"=textjoin("|", 0, split_any(";;a;;", ";"))"#), "a");
+        assert_eq!(evaluate(r#"=textjoin("|", 0, split_any("a,b", ""))"#), "a,b");
+    }
+
+    #[test]
+    fn test_text_of_lists() {
+        assert_eq!(evaluate(r#"=text(split("a,b", ","))"#), "a,b");
+        assert_eq!(evaluate(r#"=text(split("1.5;2", ";"))"#), "1.50,2");
+        assert_eq!(evaluate(r#"=text(spread(split("7", ",")))"#), "7");
+        assert_eq!(evaluate(r#"=split("a;b", ";")"#), "a,b");
+        assert_eq!(evaluate(r#"=spread(split("a;b", ";"))"#), "a,b");
+        assert_eq!(evaluate(r#"=concat("[", text(split("x y", " ")), "]")"#), "[x,y]");
+    }
+
+    #[test]
+    fn test_display_of_each_variant() {
+        use super::{CellReference, ColumnReference, LabelReference};
+
+        let cell_ref = Expression::CellReference(CellReference {
+            name: "$B2".to_string(),
+            column_name: "B".to_string(),
+            column: 2,
+            row: 2,
+            absolute_column: true,
+            absolute_row: false,
+        });
+        let column_ref = ColumnReference { name: "C".to_string(), column: 3 };
+        let number = |number: f64| Expression::Number(number);
+
+        assert_eq!(Expression::Empty.to_string(), "");
+        assert_eq!(number(2.5).to_string(), "2.50");
+        assert_eq!(number(f64::INFINITY).to_string(), "#NUM!");
+        assert_eq!(Expression::Percentage(9.0).to_string(), "9%");
+        assert_eq!(Expression::Label("total".to_string()).to_string(), "total");
+        assert_eq!(Expression::String("text".to_string()).to_string(), "text");
+        assert_eq!(Expression::List { expressions: vec![number(1.0), Expression::String("b".to_string())] }.to_string(), "1,b");
+        assert_eq!(Expression::Spread(vec![number(1.0), number(2.0)]).to_string(), "1,2");
+        assert_eq!(cell_ref.to_string(), "$B2");
+        assert_eq!(Expression::LabelReference(LabelReference { label: "fee".to_string(), n_rows: 1, n_columns: 0 }).to_string(), "@fee<1,0>");
+        assert_eq!(Expression::ColumnReference(column_ref.clone()).to_string(), "C^v");
+        assert_eq!(Expression::CopyAbove.to_string(), "^^");
+        assert_eq!(Expression::CopyEvaluated(column_ref).to_string(), "C^");
+        assert_eq!(Expression::Function { name: "sum".to_string(), params: vec![cell_ref.clone(), number(1.0)] }.to_string(), "sum($B2, 1)");
+        assert_eq!(Expression::Plus { args: vec![cell_ref.clone(), number(1.0)] }.to_string(), "($B2+1)");
+        assert_eq!(Expression::Minus { args: vec![number(3.0), number(1.0)] }.to_string(), "(3-1)");
+        assert_eq!(Expression::Multiply { args: vec![number(3.0), number(2.0)] }.to_string(), "(3*2)");
+        assert_eq!(Expression::Divide { args: vec![number(3.0), number(2.0)] }.to_string(), "(3/2)");
+        assert_eq!(Expression::Power { args: vec![number(3.0), number(2.0)] }.to_string(), "(3^2)");
+        assert_eq!(Expression::Negate(Box::new(cell_ref)).to_string(), "-$B2");
+        assert_eq!(Expression::Error(CellError::Div).to_string(), "#DIV/0!");
+    }
+
+    #[test]
+    fn test_vlookup() {
+        let mut spreadsheet = Spreadsheet::from_str("!key|!value\napple|3\npear|5\n7|seven");
+        spreadsheet.set_cell(5, 1, r#"=vlookup("pear", A^v, B^v)"#);
+        spreadsheet.set_cell(5, 2, r#"=vlookup(concat("app", "le"), A1, B1) * 2"#);
+        spreadsheet.set_cell(6, 1, "=vlookup(7, A^v, B^v)");
+        spreadsheet.set_cell(6, 2, r#"=vlookup("plum", A^v, B^v)"#);
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[4], vec!["5", "6"]);
+        assert_eq!(evaluated.spreadsheet[5], vec!["seven", "#N/A"]);
+
+        assert_eq!(evaluate(r#"=vlookup("a", 1, 2)"#), "#VALUE!");
+    }
+
+    #[test]
+    fn test_hlookup() {
+        let mut spreadsheet = Spreadsheet::from_str("jan|feb|mar\n10|20|30\n1|2|3");
+        spreadsheet.set_cell(4, 1, r#"=hlookup("feb", 1, 1)"#);
+        spreadsheet.set_cell(4, 2, r#"=hlookup("mar", A1, 2)"#);
+        spreadsheet.set_cell(4, 3, r#"=hlookup(20, 2, -1)"#);
+        spreadsheet.set_cell(5, 1, r#"=hlookup("apr", 1, 1)"#);
+        spreadsheet.set_cell(5, 2, r#"=hlookup("jan", 1, 9)"#);
+        spreadsheet.set_cell(5, 3, r#"=hlookup("jan", 1, -1)"#);
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[3], vec!["20", "3", "feb"]);
+        assert_eq!(evaluated.spreadsheet[4], vec!["#N/A", "#REF!", "#REF!"]);
+    }
+
+    #[test]
+    fn test_match_and_index() {
+        assert_eq!(evaluate(r#"=match("b", split("a,b,c", ","))"#), "2");
+        assert_eq!(evaluate(r#"=match(3, split("1;2;3", ";"))"#), "3");
+        assert_eq!(evaluate(r#"=match("z", split("a,b,c", ","))"#), "#N/A");
+        assert_eq!(evaluate(r#"=index(split("a,b,c", ","), 3)"#), "c");
+        assert_eq!(evaluate(r#"=index(split("a,b,c", ","), 4)"#), "#REF!");
+        assert_eq!(evaluate(r#"=index(split("a,b,c", ","), 0)"#), "#REF!");
+        assert_eq!(evaluate(r#"=index("only", 1)"#), "only");
+
+        let mut spreadsheet = Spreadsheet::from_str("!name|!price\napple|3\npear|5");
+        spreadsheet.set_cell(4, 1, r#"=match("pear", A^v)"#);
+        spreadsheet.set_cell(4, 2, "=index(B^v, 2)");
+        spreadsheet.set_cell(5, 1, r#"=index(B^v, match("pear", A^v)) * 10"#);
+        spreadsheet.set_cell(5, 2, r#"=index(B^v, match("plum", A^v))"#);
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[3], vec!["3", "3"]);
+        assert_eq!(evaluated.spreadsheet[4], vec!["50", "#N/A"]);
+    }
+
+    #[test]
+    fn test_concat_column() {
+        let mut spreadsheet = Spreadsheet::from_str("!fruit|\napple|\n|\npear|\n=upper(\"fig\")|");
+        spreadsheet.set_cell(2, 2, r#"=concat_column(A^v, ", ")"#);
+        spreadsheet.set_cell(3, 2, "=concat_column(A1)");
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[1][1], "apple, pear, FIG");
+        assert_eq!(evaluated.spreadsheet[2][1], "applepearFIG");
+
+        assert_eq!(evaluate(r#"=concat_column("A", ",")"#), "#VALUE!");
+    }
+
+    #[test]
+    fn test_scientific_notation() {
+        assert_eq!(evaluate("=1e3+1"), "1001");
+        assert_eq!(evaluate("=2.5E-4*1e4"), "2.50");
+        assert_eq!(evaluate("=sum(1e2, 2E1)"), "120");
+        assert_eq!(evaluate("=1_000_000/1_000"), "1000");
+    }
+
+    #[test]
+    fn test_cumsum() {
+        let spreadsheet = Spreadsheet::from_str("!amount|!total\n5|=cumsum(A^v)\n|=^^\n2.5|=^^\n-1|=cumsum(A1)");
+        let evaluated = spreadsheet.evaluate();
+        let totals = evaluated.spreadsheet.iter().map(|row| row[1].as_str()).collect::<Vec<_>>();
+        assert_eq!(totals, vec!["total", "5", "5", "7.50", "6.50"]);
+        assert!(evaluated.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_row_reference() {
+        let mut spreadsheet = Spreadsheet::from_str("!a|!b|!c\n1|2.5|=A2*2\n|4|");
+        spreadsheet.set_cell(4, 1, "=sum(2:2)");
+        spreadsheet.set_cell(4, 2, "=sum(2:3)");
+        spreadsheet.set_cell(4, 3, "=concat(3:3)");
+        spreadsheet.set_cell(3, 3, "=sum(3:3)");
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[3], vec!["5.50", "13.50", "44"]);
+        assert_eq!(evaluated.spreadsheet[2][2], "4");
+
+        assert_eq!(evaluate("=sum(5:5)"), "0");
+        assert_eq!(evaluate("=sum(0:0)"), "#REF!");
+        assert_eq!(evaluate("=sum(1:0)"), "#REF!");
+        assert!(Spreadsheet::try_from_str("=sum(1:99999999999999999999)").is_err());
+    }
+
+    #[test]
+    fn test_boolean_literals_and_if() {
+        assert_eq!(evaluate("=true"), "true");
+        assert_eq!(evaluate("=true+1"), "2");
+        assert_eq!(evaluate("=false*5+TRUE"), "1");
+        assert_eq!(evaluate("=if(true, 1, 2)"), "1");
+        assert_eq!(evaluate("=if(false, 1, 2)"), "2");
+        assert_eq!(evaluate("=if(false, 1)"), "false");
+        assert_eq!(evaluate(r#"=if(gte(3, 2), "yes", "no")"#), "yes");
+        assert_eq!(evaluate("=if(0, 1, 2)"), "2");
+        assert_eq!(evaluate("=if(1/0, 1, 2)"), "#DIV/0!");
+
+        let spreadsheet = Spreadsheet::from_str("=if(true, 1, 1/0)");
+        assert!(spreadsheet.try_evaluate().is_ok());
+    }
+
+    #[test]
+    fn test_switch() {
+        let formula = |value| format!(r#"=switch({}, "a", "first", 2, concat("sec", "ond"), "c", 1/0, "other")"#, value);
+        assert_eq!(evaluate(&formula(r#""a""#)), "first");
+        assert_eq!(evaluate(&formula("1+1")), "second");
+        assert_eq!(evaluate(&formula(r#""c""#)), "#DIV/0!");
+        assert_eq!(evaluate(&formula(r#""z""#)), "other");
+        assert_eq!(evaluate(r#"=switch("z", "a", 1)"#), "#N/A");
+        assert!(Spreadsheet::from_str(&formula(r#""a""#)).try_evaluate().is_ok());
+    }
+
+    #[test]
+    fn test_min_and_max() {
+        let mut spreadsheet = Spreadsheet::from_str("4|-2|3,12,-7.5|");
+        spreadsheet.set_cell(1, 4, r#"=max(A1, 5, spread(split(C1, ",")))"#);
+        spreadsheet.set_cell(2, 1, r#"=min(A1, B1, split(C1, ","), D2)"#);
+        spreadsheet.set_cell(2, 2, "=max(A2, D1)");
+        spreadsheet.set_cell(2, 3, "=min(1, A1/0)");
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[0][3], "12");
+        assert_eq!(evaluated.spreadsheet[1][..3], ["-7.50", "12", "#DIV/0!"]);
+
+        assert_eq!(evaluate("=max()"), "0");
+        assert_eq!(evaluate("=min(3, 1.5, 2)"), "1.50");
+    }
+
+    #[test]
+    fn test_to_formula_round_trip() {
+        let formulas = [
+            "12.5",
+            "9%",
+            r#""say \"hi\"\n""#,
+            "$B2",
+            "@fee<1>",
+            "@fee<1,2>",
+            "C^v",
+            "2:4",
+            "^^",
+            "E^",
+            "concat(A1, text(incFrom(1)), \"x\")",
+            "now()",
+            "1+2-3",
+            "1-(2-3)",
+            "(1+2)*3",
+            "2*3/4",
+            "2/(3*4)",
+            "2^3^2",
+            "(2^3)^2",
+            "-A1^2",
+            "-(1+2)",
+            "--A1",
+            "E^v+E^v*A6",
+            "sum(spread(split(D2, \",\")))+B1",
+        ];
+        for formula in formulas {
+            let expr = parse_cell_from_str(&format!("={}", formula)).unwrap();
+            assert_eq!(expr.to_formula(), formula);
+            assert_eq!(parse_cell_from_str(&format!("={}", expr.to_formula())), Some(expr));
+        }
+
+        let redundant = parse_cell_from_str("=((1*2))+(3)").unwrap();
+        assert_eq!(redundant.to_formula(), "1*2+3");
+
+        let number = |number: f64| Expression::Number(number);
+        assert_eq!(Expression::Empty.to_formula(), r#""""#);
+        assert_eq!(Expression::Label("fee".to_string()).to_formula(), "!fee");
+        assert_eq!(Expression::List { expressions: vec![number(1.0), Expression::String("b".to_string())] }.to_formula(), r#"spread(1, "b")"#);
+        assert_eq!(Expression::Spread(vec![number(1.0)]).to_formula(), "spread(1)");
+        assert_eq!(Expression::Error(CellError::NA).to_formula(), r##""#N/A""##);
+        assert_eq!(Expression::Plus { args: vec![number(1.0), number(2.0), number(3.0)] }.to_formula(), "1+2+3");
+    }
+
+    #[test]
+    fn test_countblank() {
+        let mut spreadsheet = Spreadsheet::from_str("!name|!price\napple|3\n|\npear|=concat()\n\"\"|7");
+        spreadsheet.set_cell(6, 1, "=countblank(A^v)");
+        spreadsheet.set_cell(6, 2, "=countblank(B1)");
+        spreadsheet.set_cell(7, 1, "=countblank(3:4)");
+        spreadsheet.set_cell(7, 2, "=countblank(1)");
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[5], vec!["2", "2"]);
+        assert_eq!(evaluated.spreadsheet[6], vec!["3", "#VALUE!"]);
+
+        assert_eq!(evaluate("=countblank(0:1)"), "#REF!");
+    }
+
+    #[test]
+    fn test_weighted_average() {
+        assert_eq!(evaluate(r#"=weighted_average(split("10,20,40", ","), split("1,2,1", ","))"#), "22.50");
+        assert_eq!(evaluate("=weighted_average(8, 3)"), "8");
+
+        let mut spreadsheet = Spreadsheet::from_str("2.5,3|4,0");
+        spreadsheet.set_cell(2, 1, r#"=weighted_average(split(A1, ","), split(B1, ","))"#);
+        assert_eq!(spreadsheet.evaluate().spreadsheet[1][0], "2.50");
+
+        let mut spreadsheet = Spreadsheet::from_str("10|20|40\n1|2|1");
+        spreadsheet.set_cell(3, 1, "=weighted_average(spread(A1, B1, C1), spread(A2, B2, C2))");
+        spreadsheet.set_cell(3, 2, "=weighted_average(1:1, 2:2)");
+        spreadsheet.set_cell(3, 3, "=weighted_average(spread(A1, B1), spread(1/0, 1))");
+        assert_eq!(spreadsheet.evaluate().spreadsheet[2], vec!["22.50", "22.50", "#DIV/0!"]);
+
+        assert_eq!(evaluate(r#"=weighted_average(split("1,2", ","), split("1", ","))"#), "#VALUE!");
+        assert_eq!(evaluate(r#"=weighted_average(split("1,2", ","), split("1,-1", ","))"#), "#DIV/0!");
+        assert_eq!(
+            error(r#"=weighted_average(split("1,2,3", ","), split("1,1", ","))"#),
+            SpreadsheetError::InvalidValue("weighted_average() expects as many weights as values, got 3 values and 2 weights".to_string()),
+        );
+    }
+
+    #[test]
+    fn test_variance_and_stdev() {
+        // The mean of 2, 4, 4, 4, 5, 5, 7, 9 is 5 and the squared deviations add up to 32.
+        let data = r#"spread(split("2,4,4,4,5,5,7,9", ","))"#;
+        assert_eq!(evaluate(&format!("=varp({})", data)), "4");
+        assert_eq!(evaluate(&format!("=stdevp({})", data)), "2");
+        assert_eq!(evaluate(&format!("=variance({})*7", data)), "32");
+        assert_eq!(evaluate(&format!("=stdev({})", data)), "2.14");
+        assert_eq!(evaluate(r#"=variance(1, split("2,3", ","))"#), "1");
+
+        assert_eq!(evaluate("=stdev(5)"), "#DIV/0!");
+        assert_eq!(evaluate("=varp(5)"), "0");
+        assert_eq!(evaluate("=varp()"), "#DIV/0!");
+    }
+
+    #[test]
+    fn test_concat_raw() {
+        assert_eq!(evaluate(r#"=concat("p", 1.0003)"#), "p1");
+        assert_eq!(evaluate(r#"=concat_raw("p", 1.0003)"#), "p1.0003");
+        assert_eq!(evaluate(r#"=concat_raw("id-", 0.1+0.2, "-", 7)"#), "id-0.3-7");
+        assert_eq!(evaluate(r#"=concat_raw("v", split("1.50,2", ","))"#), "v1.50,2");
+
+        let mut spreadsheet = Spreadsheet::from_str("1.0003|007");
+        spreadsheet.set_cell(1, 3, "=concat_raw(A1, \"/\", B1)");
+        assert_eq!(spreadsheet.evaluate().spreadsheet[0][2], "1.0003/007");
+    }
+
+    #[test]
+    fn test_errors_propagate_through_functions() {
+        assert_eq!(evaluate("=abs(1/0)"), "#DIV/0!");
+        assert_eq!(evaluate("=sqrt(1/0)"), "#DIV/0!");
+        assert_eq!(evaluate("=clamp(1/0, 0, 1)"), "#DIV/0!");
+        assert_eq!(evaluate(r#"=text(1/0, "0.00")"#), "#DIV/0!");
+        assert_eq!(evaluate("=gte(1/0, 1)"), "#DIV/0!");
+        assert_eq!(evaluate("=gte(1, sqrt(0-1))"), "#NUM!");
+        assert_eq!(evaluate(r#"=concat("total: ", @missing<1>)"#), "#NAME?");
+        assert_eq!(evaluate("=isnumber(1/0)"), "false");
+        assert_eq!(evaluate("=abs(1, 1/0)"), "#VALUE!");
+    }
+
+    #[test]
+    fn test_blank_and_boolean_coercion() {
+        let evaluated = Spreadsheet::from_str("1||=A1+B1|=sum(A1, B1, lte(1, 2), gte(1, 2))").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0][2], "1");
+        assert_eq!(evaluated.spreadsheet[0][3], "2");
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A 1-based `(row, column)` cell position.
+pub(crate) type Position = (usize, usize);
+
+/// Orders cells so that every cell comes after the cells it depends on. Independent cells keep
+/// the usual row-major order. Dependencies on positions that aren't keys of the map are ignored.
+///
+/// When the dependencies contain a cycle, returns the cells that are part of one instead. Cells
+/// that merely depend on a cycle are left out.
+pub(crate) fn topological_order(dependencies: &BTreeMap<Position, Vec<Position>>) -> Result<Vec<Position>, Vec<Position>> {
+    let mut pending: BTreeMap<Position, usize> = BTreeMap::new();
+    let mut dependents: BTreeMap<Position, BTreeSet<Position>> = BTreeMap::new();
+
+    for (&position, references) in dependencies {
+        let references = references
+            .iter()
+            .filter(|reference| dependencies.contains_key(reference))
+            .collect::<BTreeSet<_>>();
+        pending.insert(position, references.len());
+        for &reference in references {
+            dependents.entry(reference).or_default().insert(position);
+        }
+    }
+
+    let mut ready = pending
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(position, _)| *position)
+        .collect::<BTreeSet<_>>();
+    let mut order = vec![];
+
+    while let Some(position) = ready.pop_first() {
+        order.push(position);
+        for dependent in dependents.get(&position).into_iter().flatten() {
+            let count = pending.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(*dependent);
+            }
+        }
+    }
+
+    if order.len() == dependencies.len() {
+        return Ok(order);
+    }
+
+    // Whatever is left is either on a cycle or downstream of one. Peel off the cells nothing else
+    // left depends on until only the cycles remain.
+    let mut remaining = pending
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(position, _)| position)
+        .collect::<BTreeSet<_>>();
+    loop {
+        let downstream = remaining
+            .iter()
+            .filter(|position| !dependents.get(position).is_some_and(|dependents| dependents.iter().any(|dependent| remaining.contains(dependent))))
+            .copied()
+            .collect::<Vec<_>>();
+        if downstream.is_empty() {
+            return Err(remaining.into_iter().collect());
+        }
+        for position in downstream {
+            remaining.remove(&position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topological_order() {
+        let dependencies = BTreeMap::from([
+            ((1, 1), vec![(2, 1)]),
+            ((1, 2), vec![]),
+            ((2, 1), vec![(1, 2), (9, 9)]),
+        ]);
+        assert_eq!(topological_order(&dependencies), Ok(vec![(1, 2), (2, 1), (1, 1)]));
+
+        let dependencies = BTreeMap::from([
+            ((1, 1), vec![(1, 2)]),
+            ((1, 2), vec![(1, 1)]),
+            ((1, 3), vec![(1, 1)]),
+            ((2, 1), vec![(2, 1)]),
+            ((2, 2), vec![]),
+        ]);
+        assert_eq!(topological_order(&dependencies), Err(vec![(1, 1), (1, 2), (2, 1)]));
+    }
+}
@@ -2,92 +2,693 @@
 extern crate pest_derive;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
-use crate::expression::Expression;
-use crate::parser::{LabelsMap, parse, Sheet};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+use std::time::SystemTime;
+use crate::expression::{offset_from_label, RECURSION_LIMIT};
+use crate::parser::{LabelsMap, pad_rows, parse_cell_from_str, parse_with_options, Sheet};
 
+pub use crate::builder::SpreadsheetBuilder;
+pub use crate::error::{CellError, LoadError, ParseError, SpreadsheetError, Warning, WarningKind};
+pub use crate::expression::{CellReference, ColumnReference, Expression, LabelReference, RowReference};
+pub use crate::locale::Locale;
+pub use crate::parser::{parse_sheet, ParseOptions, ParsedSheet};
+
+mod builder;
+mod csv;
+mod date;
+mod error;
 mod expression;
+mod graph;
+mod locale;
 mod parser;
 
-struct Spreadsheet {
+/// A function added with `Spreadsheet::register_function`.
+pub type CustomFunction = dyn Fn(&[Expression]) -> Expression;
+
+pub struct Spreadsheet {
     rows: Sheet,
     labels_map: LabelsMap,
     evaluating_row: RefCell<usize>,
     evaluating_column: RefCell<usize>,
+    /// How many cells are being evaluated inside each other right now, through references.
+    depth: RefCell<usize>,
+    recursion_limit: usize,
+    /// Whether text that isn't a number turns a cell into `#VALUE!` when used as one, instead of
+    /// counting as `0`.
+    strict: bool,
+    /// Values of cells that were already evaluated, keyed by 1-based position. Only filled while
+    /// `evaluate_topologically` runs.
+    cache: RefCell<HashMap<(usize, usize), Expression>>,
+    /// Results of function calls, keyed by the position of the cell they're in and the call itself.
+    /// Only kept while a cell is being evaluated, see `Expression::evaluate`.
+    subexpressions: RefCell<HashMap<(usize, usize), HashMap<Expression, Expression>>>,
+    locale: Locale,
+    warnings: RefCell<Vec<Warning>>,
+    /// Problems that stopped cells from evaluating, with the 1-based position of the cell.
+    errors: RefCell<Vec<(usize, usize, SpreadsheetError)>>,
+    /// Functions registered with `register_function`, keyed by their lowercase name.
+    functions: HashMap<String, Box<CustomFunction>>,
+    clock: Box<dyn Fn() -> SystemTime>,
 }
 
 impl Spreadsheet {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(input: &str) -> Self {
-        let (rows, labels_map) = parse(input.trim()).unwrap();
-        Self { rows, labels_map, evaluating_row: RefCell::new(0), evaluating_column: RefCell::new(0) }
+        Self::try_from_str(input).unwrap_or_else(|error| panic!("{}", error))
     }
 
-    pub fn evaluate(self) -> EvaluatedSpreadsheet {
-        let mut columns_length: HashMap<usize, usize> = HashMap::new();
+    pub fn try_from_str(input: &str) -> Result<Self, ParseError> {
+        Self::try_from_str_with_options(input, ParseOptions::default())
+    }
+
+    /// Like `try_from_str`, but reads the sheet the way `options` say, e.g. skipping blank lines.
+    pub fn try_from_str_with_options(input: &str, options: ParseOptions) -> Result<Self, ParseError> {
+        let (rows, labels_map) = parse_with_options(input.trim(), options)?;
+        Ok(Self::from_parts(rows, labels_map))
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, LoadError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        Ok(Self::try_from_str(&input)?)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    /// Reads comma-separated values. Fields starting with `=` or `!` are parsed as formulas and
+    /// labels, everything else is kept as a literal value. Panics if a label is defined twice.
+    pub fn from_csv(input: &str) -> Self {
+        Self::try_from_csv(input).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_from_csv(input: &str) -> Result<Self, ParseError> {
+        Self::try_from_delimited(input, ',')
+    }
+
+    /// Like `from_csv`, but for tab-separated values.
+    pub fn from_tsv(input: &str) -> Self {
+        Self::try_from_tsv(input).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_from_tsv(input: &str) -> Result<Self, ParseError> {
+        Self::try_from_delimited(input, '\t')
+    }
+
+    fn try_from_delimited(input: &str, delimiter: char) -> Result<Self, ParseError> {
+        let mut builder = Self::builder();
+
+        for fields in csv::parse_delimited(input, delimiter) {
+            let row = fields
+                .into_iter()
+                .map(|field| match field.chars().next() {
+                    None => Expression::Empty,
+                    Some('=' | '!') => parse_cell_from_str(&field).unwrap_or(Expression::String(field)),
+                    Some(_) => Expression::String(field),
+                })
+                .collect();
+            builder.push_row(row);
+        }
+
+        builder.try_build()
+    }
+
+    pub fn builder() -> SpreadsheetBuilder {
+        SpreadsheetBuilder::default()
+    }
 
-        let result = self.rows
+    pub(crate) fn from_parts(mut rows: Sheet, labels_map: LabelsMap) -> Self {
+        pad_rows(&mut rows);
+        Self {
+            rows,
+            labels_map,
+            evaluating_row: RefCell::new(0),
+            evaluating_column: RefCell::new(0),
+            depth: RefCell::new(0),
+            recursion_limit: RECURSION_LIMIT,
+            strict: false,
+            cache: RefCell::new(HashMap::new()),
+            subexpressions: RefCell::new(HashMap::new()),
+            locale: Locale::DEFAULT,
+            warnings: RefCell::new(vec![]),
+            errors: RefCell::new(vec![]),
+            functions: HashMap::new(),
+            clock: Box::new(SystemTime::now),
+        }
+    }
+
+    /// Sets how deep evaluation may go before giving up, 256 by default. It bounds both the chain
+    /// of cells referencing each other and the steps taken to evaluate a single cell, so deeply
+    /// nested sheets can raise it and untrusted ones can lower it.
+    pub fn with_recursion_limit(mut self, recursion_limit: usize) -> Self {
+        self.recursion_limit = recursion_limit;
+        self
+    }
+
+    /// Makes cells that use text as a number evaluate to `#VALUE!`. By default the text counts as
+    /// `0` and only a warning is recorded.
+    pub fn with_strict_mode(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Replaces the source of the current time used by `now()` and `today()`.
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Adds a function that formulas can call by `name`, case-insensitively. It's looked up before
+    /// the built-in functions, so it can also replace one of them. Its params arrive evaluated,
+    /// with spreads expanded, and `Expression::as_number` reads them as numbers.
+    pub fn register_function(&mut self, name: &str, function: impl Fn(&[Expression]) -> Expression + 'static) {
+        self.functions.insert(name.to_lowercase(), Box::new(function));
+    }
+
+    pub fn evaluate(&self) -> EvaluatedSpreadsheet {
+        self.warnings.borrow_mut().clear();
+        self.errors.borrow_mut().clear();
+        let mut evaluated = EvaluatedSpreadsheet::new(self.iter_values().collect());
+        evaluated.warnings = self.warnings.take();
+        evaluated.errors = self.errors.take();
+        evaluated.header_row = self.headers().is_some();
+        evaluated
+    }
+
+    /// Like `evaluate`, but fails with the first problem that stopped a cell from evaluating, like a
+    /// division by zero, a call to an unknown function or a reference to a cell that isn't there,
+    /// instead of rendering its error value.
+    pub fn try_evaluate(&self) -> Result<EvaluatedSpreadsheet, SpreadsheetError> {
+        let evaluated = self.evaluate();
+        match evaluated.errors.first() {
+            Some((_, _, error)) => Err(error.clone()),
+            None => Ok(evaluated),
+        }
+    }
+
+    /// Sets how numbers are written in literal cells, e.g. `Locale::EUROPEAN` for `1.000,50`.
+    /// Literal cells that are numbers in that locale become numbers, and render the usual way.
+    /// Text that formulas use as a number, like the parts of a split cell, is read in the locale
+    /// too.
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        let rows = std::mem::take(&mut self.rows);
+        self.rows = rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|cell| self.localized(cell)).collect())
+            .collect();
+        self
+    }
+
+    /// Reads text as a number, written the way Rust writes numbers or else the way the sheet's
+    /// locale does. Computed numbers are rendered the first way, so `1.234` stays a bit more than
+    /// one in `Locale::EUROPEAN` while `1,5` is one and a half.
+    pub(crate) fn parse_number(&self, text: &str) -> Option<f64> {
+        text.parse().ok().filter(|number: &f64| number.is_finite()).or_else(|| self.locale.parse_number(text))
+    }
+
+    /// Turns a literal cell into a number when it is written as one in the sheet's locale.
+    fn localized(&self, cell: Expression) -> Expression {
+        let Expression::String(text) = &cell else { return cell };
+        if self.locale == Locale::DEFAULT {
+            return cell;
+        }
+
+        let number = match text.strip_suffix('%') {
+            Some(percent) => self.locale.parse_number(percent).map(Expression::Percentage),
+            None => self.locale.parse_number(text).map(Expression::Number),
+        };
+        number.unwrap_or(cell)
+    }
+
+    /// Evaluates every cell exactly once, after the cells it references, instead of evaluating
+    /// references again each time they are used. Fails when cells reference each other in a loop.
+    pub fn evaluate_topologically(&self) -> Result<EvaluatedSpreadsheet, SpreadsheetError> {
+        let mut dependencies = BTreeMap::new();
+        for (row_index, row) in self.rows.iter().enumerate() {
+            for (column_index, cell) in row.iter().enumerate() {
+                let mut references = vec![];
+                cell.references(self, row_index + 1, column_index + 1, &mut references);
+                dependencies.insert((row_index + 1, column_index + 1), references);
+            }
+        }
+
+        self.warnings.borrow_mut().clear();
+        self.errors.borrow_mut().clear();
+        let order = graph::topological_order(&dependencies).map_err(|cells| SpreadsheetError::Cycle { cells })?;
+        for (row_number, column_number) in order {
+            let value = self.evaluate_reference(row_number, column_number);
+            self.cache.borrow_mut().insert((row_number, column_number), value);
+        }
+
+        let mut values = self.cache.take();
+        let values = self.rows
             .iter()
+            .enumerate()
+            .map(|(row_index, row)| (0..row.len()).map(|column_index| values.remove(&(row_index + 1, column_index + 1)).unwrap()).collect())
+            .collect();
+        let mut evaluated = EvaluatedSpreadsheet::new(values);
+        evaluated.warnings = self.warnings.take();
+        evaluated.errors = self.errors.take();
+        evaluated.header_row = self.headers().is_some();
+        Ok(evaluated)
+    }
+
+    /// Lazily evaluates the sheet one row at a time. Values are not padded to a column width.
+    pub fn iter_evaluated(&self) -> impl Iterator<Item = Vec<String>> + '_ {
+        self.iter_values().map(|row| row.iter().map(Expression::to_string).collect())
+    }
+
+    /// Like `iter_evaluated`, but yields the final values instead of their rendered text.
+    fn iter_values(&self) -> impl Iterator<Item = Vec<Expression>> + '_ {
+        self.rows
+            .iter()
+            .enumerate()
             .map(
-                |row| {
-                    self.evaluating_row.replace_with(|&mut row_number| row_number + 1);
-                    self.evaluating_column.replace(0);
+                |(row_index, row)| {
                     row
                         .iter()
                         .enumerate()
-                        .map(|(column_index, cell)| {
-                            self.evaluating_column.replace_with(|&mut column_number| column_number + 1);
-                            let value = cell.evaluate_recursively(&self).to_string();
-
-                            let column_length = columns_length.entry(column_index).or_default();
-                            if value.len() > *column_length {
-                                *column_length = value.len()
-                            }
-
-                            value
-                        })
-                        .collect::<Vec<String>>()
+                        .map(|(column_index, cell)| self.evaluate_at(row_index + 1, column_index + 1, cell))
+                        .collect::<Vec<Expression>>()
                 }
-            ).collect::<Vec<Vec<String>>>();
+            )
+    }
+
+    /// Iterates over every cell as parsed, with its 1-based row and column.
+    pub fn iter_cells(&self) -> impl Iterator<Item = (usize, usize, &Expression)> + '_ {
+        self.rows.iter().enumerate().flat_map(|(row_index, row)| {
+            row.iter()
+                .enumerate()
+                .map(move |(column_index, cell)| (row_index + 1, column_index + 1, cell))
+        })
+    }
+
+    /// Returns the number of rows and columns. Rows are padded to the same length, so every row
+    /// has `column_count` cells.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.row_count(), self.column_count())
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.rows.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// Returns the names of all labels, ordered by their position in the sheet.
+    pub fn labels(&self) -> Vec<&str> {
+        let mut labels = self.labels_map.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|(_, position)| **position);
+        labels.into_iter().map(|(label, _)| label.as_str()).collect()
+    }
+
+    /// Evaluates the cell `offset` rows below `label`, the same way `@label<offset>` does.
+    pub fn get_by_label(&self, label: &str, offset: usize) -> Option<String> {
+        let (row_number, column_number) = offset_from_label(*self.labels_map.get(label)?, offset, 0)?;
+        let cell = self.get_cell(row_number, column_number)?;
+        Some(self.evaluate_at(row_number, column_number, cell).to_string())
+    }
+
+    /// Returns the names of the labels in the first row, when every cell of it is a label.
+    pub fn headers(&self) -> Option<Vec<String>> {
+        let first_row = self.rows.first()?;
+        first_row
+            .iter()
+            .map(|cell| match cell {
+                Expression::Label(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Evaluates the cell in the column headed `header` on the given 1-based row, see `headers`.
+    pub fn get_by_header(&self, header: &str, row_number: usize) -> Option<String> {
+        let column_index = self.headers()?.iter().position(|name| name == header)?;
+        let cell = self.get_cell(row_number, column_index + 1)?;
+        Some(self.evaluate_at(row_number, column_index + 1, cell).to_string())
+    }
+
+    /// Evaluates only the cell at the given 1-based position and renders it the same way
+    /// `evaluate` would.
+    pub fn evaluate_cell(&self, row_number: usize, column_number: usize) -> String {
+        self.evaluate_reference(row_number, column_number).to_string()
+    }
+
+    /// Evaluates the cell at the given 1-based position, or `#REF!` if it lies outside of the sheet.
+    pub(crate) fn evaluate_reference(&self, row_number: usize, column_number: usize) -> Expression {
+        if let Some(value) = self.cache.borrow().get(&(row_number, column_number)) {
+            return value.clone();
+        }
+        match self.get_cell(row_number, column_number) {
+            Some(cell) => self.evaluate_at(row_number, column_number, cell),
+            None => self.fail(SpreadsheetError::InvalidReference(format!("{}{}", column_name_from_index(column_number), row_number))),
+        }
+    }
+
+    /// Evaluates `cell` as if it was located at the given 1-based position, so that relative
+    /// operations like `^^` and `A^` resolve against that position.
+    pub(crate) fn evaluate_at(&self, row_number: usize, column_number: usize, cell: &Expression) -> Expression {
+        let depth = *self.depth.borrow() + 1;
+        if depth > self.recursion_limit {
+            return self.fail(SpreadsheetError::RecursionLimit);
+        }
+        self.depth.replace(depth);
+        let previous_row = self.evaluating_row.replace(row_number);
+        let previous_column = self.evaluating_column.replace(column_number);
+
+        let mut value = cell.evaluate_recursively(self);
+        let used_text_as_number = self
+            .errors
+            .borrow()
+            .iter()
+            .any(|(row, column, error)| (*row, *column) == (row_number, column_number) && matches!(error, SpreadsheetError::TextAsNumber(_)));
+        if used_text_as_number {
+            value = Expression::Error(CellError::Value);
+        }
+
+        self.evaluating_row.replace(previous_row);
+        self.evaluating_column.replace(previous_column);
+        self.depth.replace(depth - 1);
+        if depth == 1 {
+            self.subexpressions.borrow_mut().clear();
+        }
+        value
+    }
+
+    /// Writes the sheet back in the format it's read from, with `|` between cells and formulas
+    /// instead of their values, so a sheet can be loaded, changed with `set_cell` and saved again.
+    /// Comments aren't kept.
+    pub fn to_source(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| row.iter().map(Expression::to_source).collect::<Vec<_>>().join("|"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Renders the parsed formula of the cell at the given 1-based position as an indented tree,
+    /// see `Expression::explain`.
+    pub fn explain(&self, row_number: usize, column_number: usize) -> String {
+        match self.get_cell(row_number, column_number) {
+            Some(cell) => cell.explain(),
+            None => Expression::Error(CellError::Ref).explain(),
+        }
+    }
+
+    /// Replaces the cell at the given 1-based position with `formula`, growing the sheet with
+    /// empty cells if the position lies outside of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `row_number` or `column_number` is 0, or if `formula` is a label that another
+    /// cell already defines.
+    pub fn set_cell(&mut self, row_number: usize, column_number: usize, formula: &str) {
+        assert!(row_number > 0 && column_number > 0, "cell positions are 1-based, got ({}, {})", row_number, column_number);
+        let expr = self.localized(parse_cell_from_str(formula.trim()).unwrap_or(Expression::Empty));
+
+        if let Expression::Label(label) = &expr {
+            if let Some(&(first_row, first_column)) = self.labels_map.get(label) {
+                if (first_row, first_column) != (row_number - 1, column_number - 1) {
+                    let error = ParseError::DuplicateLabel {
+                        label: label.to_string(),
+                        first: (first_row + 1, first_column + 1),
+                        second: (row_number, column_number),
+                    };
+                    panic!("{}", error);
+                }
+            }
+        }
+
+        if self.rows.len() < row_number {
+            self.rows.resize(row_number, vec![]);
+        }
+        let row = &mut self.rows[row_number - 1];
+        if row.len() < column_number {
+            row.resize(column_number, Expression::Empty);
+        }
+        pad_rows(&mut self.rows);
+        let row = &mut self.rows[row_number - 1];
+
+        let position = (row_number - 1, column_number - 1);
+        if let Expression::Label(label) = &row[column_number - 1] {
+            if self.labels_map.get(label) == Some(&position) {
+                self.labels_map.remove(label);
+            }
+        }
+        if let Expression::Label(label) = &expr {
+            self.labels_map.insert(label.to_string(), position);
+        }
+
+        row[column_number - 1] = expr;
+    }
 
-        EvaluatedSpreadsheet { spreadsheet: result, columns_length }
+    /// Records a warning for the cell that is being evaluated. The same warning is only kept once,
+    /// even if the cell is evaluated again through references.
+    pub(crate) fn warn(&self, kind: WarningKind) {
+        let warning = Warning { row: *self.evaluating_row.borrow(), column: *self.evaluating_column.borrow(), kind };
+        let mut warnings = self.warnings.borrow_mut();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
     }
 
-    pub fn to_string(self) -> String {
-        self.evaluate().to_string()
+    /// Records a problem that stopped the cell being evaluated, and returns the error value the
+    /// cell evaluates to instead.
+    pub(crate) fn fail(&self, error: SpreadsheetError) -> Expression {
+        let value = Expression::Error(error.cell_error());
+        let failure = (*self.evaluating_row.borrow(), *self.evaluating_column.borrow(), error);
+        let mut errors = self.errors.borrow_mut();
+        if !errors.contains(&failure) {
+            errors.push(failure);
+        }
+        value
     }
 
-    pub(crate) fn get_cell(&self, row_number: usize, column_number: usize) -> Expression {
+    pub(crate) fn get_cell(&self, row_number: usize, column_number: usize) -> Option<&Expression> {
         self.rows
-            .get(row_number - 1).expect(format!("referencing unknown row {}", row_number).as_str())
-            .get(column_number - 1).expect(format!("referencing unknown column {}", column_number).as_str())
-            .clone()
+            .get(row_number.checked_sub(1)?)?
+            .get(column_number.checked_sub(1)?)
     }
 }
 
-struct EvaluatedSpreadsheet {
+pub struct EvaluatedSpreadsheet {
+    /// The final value of every cell. Results of arithmetic stay numbers, while text, whether
+    /// literal or computed, stays a string.
+    values: Vec<Vec<Expression>>,
     spreadsheet: Vec<Vec<String>>,
     columns_length: HashMap<usize, usize>,
+    warnings: Vec<Warning>,
+    /// Problems that stopped cells from evaluating, with the 1-based position of the cell.
+    errors: Vec<(usize, usize, SpreadsheetError)>,
+    verbatim_strings: bool,
+    /// Whether the first row holds the labels heading each column, see `Spreadsheet::headers`.
+    header_row: bool,
 }
 
 impl EvaluatedSpreadsheet {
-    pub fn to_string(self) -> String {
+    fn new(values: Vec<Vec<Expression>>) -> Self {
+        Self::rendered(values, false)
+    }
+
+    fn rendered(values: Vec<Vec<Expression>>, verbatim_strings: bool) -> Self {
+        let spreadsheet = values
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| match value {
+                        Expression::String(text) if verbatim_strings => text.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .collect::<Vec<_>>();
+        let mut columns_length: HashMap<usize, usize> = HashMap::new();
+
+        for row in &spreadsheet {
+            for (column_index, value) in row.iter().enumerate() {
+                // Width in characters rather than bytes, the same unit `format!` pads with.
+                let width = escape_line_breaks(value).chars().count();
+                let column_length = columns_length.entry(column_index).or_default();
+                if width > *column_length {
+                    *column_length = width
+                }
+            }
+        }
+
+        Self { values, spreadsheet, columns_length, warnings: vec![], errors: vec![], verbatim_strings, header_row: false }
+    }
+
+    /// Swaps rows and columns. Short rows are padded with empty cells first, so the result is
+    /// always rectangular.
+    pub fn transpose(&self) -> EvaluatedSpreadsheet {
+        let mut evaluated = EvaluatedSpreadsheet::rendered(transposed(&self.values, Expression::String(String::new())), self.verbatim_strings);
+        evaluated.warnings = self.warnings
+            .iter()
+            .map(|warning| Warning { row: warning.column, column: warning.row, ..warning.clone() })
+            .collect();
+        evaluated.errors = self.errors
+            .iter()
+            .map(|(row, column, error)| (*column, *row, error.clone()))
+            .collect();
+        evaluated
+    }
+
+    /// Renders text exactly as it is stored, instead of reformatting the text that looks like a
+    /// number. A cell containing `007` stays `007` rather than becoming `7`, and so does a formula
+    /// referencing it. Only numbers computed by formulas are formatted.
+    pub fn with_verbatim_strings(&self) -> EvaluatedSpreadsheet {
+        let mut evaluated = EvaluatedSpreadsheet::rendered(self.values.clone(), true);
+        evaluated.warnings = self.warnings.clone();
+        evaluated.errors = self.errors.clone();
+        evaluated.header_row = self.header_row;
+        evaluated
+    }
+
+    /// Suspicious things noticed while the sheet was evaluated, in the order they happened.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Problems that stopped cells from evaluating, in the order they happened, with the 1-based
+    /// `(row, column)` of the cell that failed. The cell itself only shows the short error value.
+    pub fn errors(&self) -> Vec<(usize, usize, SpreadsheetError)> {
+        self.errors.clone()
+    }
+
+    /// Describes every error with the cell it happened in, one per line, like
+    /// `#DIV/0! at B3: division by zero`.
+    pub fn diagnostics(&self) -> String {
+        self.errors
+            .iter()
+            .map(|(row, column, error)| format!("{} at {}{}: {}", error.cell_error(), column_name_from_index(*column), row, error))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the rendered cells and column widths as plain data, for handing the result over to
+    /// other formats.
+    pub fn to_value(&self) -> SheetValue {
+        let columns = self.spreadsheet.iter().map(Vec::len).max().unwrap_or(0);
+        SheetValue {
+            rows: self.spreadsheet.clone(),
+            column_widths: (0..columns).map(|column_index| self.columns_length[&column_index]).collect(),
+        }
+    }
+
+    /// Renders the sheet like `Display` does, with every column padded to its widest cell, but
+    /// joins the cells with `separator` instead of `" | "`.
+    pub fn to_string_with_separator(&self, separator: &str) -> String {
+        self.render(separator, true)
+    }
+
+    /// Renders the sheet with cells joined by `separator` and no width alignment, which keeps
+    /// the output compact and diff friendly.
+    pub fn to_string_unpadded(&self, separator: &str) -> String {
+        self.render(separator, false)
+    }
+
+    /// Renders the sheet as tab separated values, ready to paste into a spreadsheet app. Tabs and
+    /// line breaks inside a cell become spaces so they can't split it.
+    pub fn to_tsv(&self) -> String {
+        self.spreadsheet
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.replace("\r\n", " ").replace(['\t', '\r', '\n'], " ")).collect::<Vec<_>>().join("\t"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Renders the sheet as an HTML `<table>`, one `<tr>` per row. When the first row holds the
+    /// labels heading each column, its cells are `<th>` instead of `<td>`.
+    pub fn to_html(&self) -> String {
+        let mut html = String::from("<table>\n");
+        for (row_index, row) in self.spreadsheet.iter().enumerate() {
+            let tag = if row_index == 0 && self.header_row { "th" } else { "td" };
+            html.push_str("<tr>");
+            for cell in row {
+                html.push_str(&format!("<{tag}>{}</{tag}>", escape_html(cell), tag = tag));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>");
+        html
+    }
+
+    fn render(&self, separator: &str, padded: bool) -> String {
         self.spreadsheet
             .iter()
             .map(
                 |row| row
                     .iter()
                     .enumerate()
-                    .map(|(column, cell)| format!("{:indent$}", cell, indent = self.columns_length.get(&column).unwrap()))
+                    .map(|(column, cell)| {
+                        let width = if padded { self.columns_length[&column] } else { 0 };
+                        format!("{:width$}", escape_line_breaks(cell))
+                    })
                     .collect::<Vec<String>>()
-                    .join(" | ")
-                    .to_string()
+                    .join(separator)
             )
             .collect::<Vec<String>>()
             .join("\n")
-            .to_string()
     }
 }
 
+/// Writes line breaks inside a cell as `\n` and `\r`, so a multi-line cell stays on its row when
+/// the sheet is rendered as text.
+fn escape_line_breaks(cell: &str) -> String {
+    cell.replace('\r', "\\r").replace('\n', "\\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// An evaluated sheet as plain data: the rendered value of every cell, and the width in
+/// characters of every column.
+/// With the `serde` feature it implements `Serialize` and `Deserialize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SheetValue {
+    pub rows: Vec<Vec<String>>,
+    pub column_widths: Vec<usize>,
+}
+
+impl std::fmt::Display for Spreadsheet {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.evaluate().fmt(fmt)
+    }
+}
+
+impl std::fmt::Display for EvaluatedSpreadsheet {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str(&self.to_string_with_separator(" | "))
+    }
+}
+
+/// Swaps rows and columns, padding short rows with `fill` first.
+fn transposed<T: Clone>(rows: &[Vec<T>], fill: T) -> Vec<Vec<T>> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+
+    (0..columns)
+        .map(|column_index| {
+            rows
+                .iter()
+                .map(|row| row.get(column_index).cloned().unwrap_or_else(|| fill.clone()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the letters of a column from its 1-based index: `1` is `A`, `26` is `Z` and `27` is
+/// `AA`. There is no column 0, so it gives an empty name.
 pub fn column_name_from_index(column: usize) -> String {
     let mut column_name = String::new();
     let mut column = column;
@@ -97,27 +698,34 @@ pub fn column_name_from_index(column: usize) -> String {
         let char = char::from_u32('A' as u32 + char_val as u32).unwrap();
 
         column_name.insert(0, char);
-        column = (column - char_val) / 26;
+        column = (column - 1) / 26;
     }
 
-    return format!("{}", column_name);
+    column_name
 }
 
+/// Returns the 1-based index of a column from its letters, the inverse of
+/// `column_name_from_index`. An empty name gives 0.
+///
+/// # Panics
+///
+/// Panics if the index doesn't fit in a `usize`.
 pub fn column_index_from_name(column: &str) -> usize {
-    let mut index = 0;
-    let mut mul = 1;
-
-    for c in column.chars().rev() {
-        index += (c as usize - 'A' as usize + 1) * mul;
-        mul *= 26;
-    }
+    checked_column_index_from_name(column).unwrap_or_else(|| panic!("column {} is too large", column))
+}
 
-    return index;
+/// Like `column_index_from_name`, but returns `None` for an index that doesn't fit in a `usize`.
+pub(crate) fn checked_column_index_from_name(column: &str) -> Option<usize> {
+    column
+        .chars()
+        .try_fold(0usize, |index, c| index.checked_mul(26)?.checked_add(c as usize - 'A' as usize + 1))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
     #[test]
     fn test_from_str() {
@@ -167,4 +775,672 @@ mod tests {
         // assert_eq!(parsed[2][0], "2022-02-21".to_owned());
         // assert_eq!(parsed[2][1], "t_2".to_owned());
     }
+
+    #[test]
+    fn test_comments_do_not_shift_references() {
+        let input = "# totals\n1|2\n# computed from the row above\n=A1+B1|=B1\n";
+
+        let evaluated = Spreadsheet::from_str(input).evaluate();
+
+        assert_eq!(evaluated.spreadsheet.len(), 2);
+        assert_eq!(evaluated.spreadsheet[1], vec!["3", "2"]);
+    }
+
+    #[test]
+    fn test_set_cell() {
+        let mut spreadsheet = Spreadsheet::from_str("!price|\n2|=A2*3");
+        assert_eq!(spreadsheet.evaluate().spreadsheet[1][1], "6");
+
+        spreadsheet.set_cell(2, 2, "=A2*10");
+        assert_eq!(spreadsheet.evaluate().spreadsheet[1][1], "20");
+
+        spreadsheet.set_cell(1, 1, "!cost");
+        spreadsheet.set_cell(3, 1, "=@cost<1>+1");
+        assert!(!spreadsheet.labels_map.contains_key("price"));
+        assert_eq!(spreadsheet.evaluate().spreadsheet[2][0], "3");
+    }
+
+    #[test]
+    #[should_panic(expected = "cell positions are 1-based, got (0, 1)")]
+    fn test_set_cell_row_zero() {
+        Spreadsheet::from_str("1|2").set_cell(0, 1, "3");
+    }
+
+    #[test]
+    #[should_panic(expected = "label 'a' is defined twice, at A1 and A3")]
+    fn test_set_cell_duplicate_label() {
+        Spreadsheet::from_str("!a|!b\n1|2").set_cell(3, 1, "!a");
+    }
+
+    #[test]
+    fn test_set_cell_label_round_trip() {
+        let mut spreadsheet = Spreadsheet::from_str("!a|!b\n1|2");
+        spreadsheet.set_cell(1, 1, "!a");
+        spreadsheet.set_cell(3, 1, "!c");
+
+        let source = spreadsheet.to_source();
+        assert_eq!(source, "!a|!b\n1|2\n!c|");
+        assert!(Spreadsheet::try_from_str(&source).is_ok());
+    }
+
+    #[test]
+    fn test_evaluate_cell() {
+        let spreadsheet = Spreadsheet::from_str("!a|!b\n1|=A2*2\n3|=B^+A3\n=B^v*10|");
+        let evaluated = spreadsheet.evaluate();
+
+        for (row_index, row) in evaluated.spreadsheet.iter().enumerate() {
+            for (column_index, value) in row.iter().enumerate() {
+                assert_eq!(&spreadsheet.evaluate_cell(row_index + 1, column_index + 1), value);
+            }
+        }
+        assert_eq!(spreadsheet.evaluate_cell(3, 2), "5");
+        assert_eq!(spreadsheet.evaluate_cell(9, 9), "#REF!");
+    }
+
+    #[test]
+    fn test_labels() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+
+        assert_eq!(
+            spreadsheet.labels(),
+            vec!["date", "transaction_id", "tokens", "token_prices", "total_cost", "fee", "cost_threshold", "adjusted_cost", "cost_too_high"],
+        );
+        assert_eq!(spreadsheet.get_by_label("cost_threshold", 1), Some("10000".to_string()));
+        assert_eq!(spreadsheet.get_by_label("adjusted_cost", 0), Some("adjusted_cost".to_string()));
+        assert_eq!(spreadsheet.get_by_label("adjusted_cost", 1), Some("50797.65".to_string()));
+        assert_eq!(spreadsheet.get_by_label("adjusted_cost", 10), None);
+        assert_eq!(spreadsheet.get_by_label("missing", 1), None);
+    }
+
+    #[test]
+    fn test_label_reference_with_column_offset() {
+        let mut spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+        spreadsheet.set_cell(11, 1, "=@adjusted_cost<1,0>");
+        spreadsheet.set_cell(11, 2, "=@fee<1, 1>");
+        spreadsheet.set_cell(11, 3, "=@date<2,4>");
+
+        assert_eq!(spreadsheet.evaluate_cell(11, 1), spreadsheet.get_by_label("adjusted_cost", 1).unwrap());
+        assert_eq!(spreadsheet.evaluate_cell(11, 2), "10000");
+        assert_eq!(spreadsheet.evaluate_cell(11, 3), spreadsheet.get_by_label("total_cost", 2).unwrap());
+    }
+
+    #[test]
+    fn test_label_offset_too_large() {
+        for input in ["!a\n=@a<99999999999999999999999>", "!a\n=@a<1,99999999999999999999999>"] {
+            assert!(matches!(Spreadsheet::try_from_str(input), Err(ParseError::Syntax(_))), "{}", input);
+            assert!(parse_sheet(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let error = Spreadsheet::try_from_str("!fee|!total\n1|2\n!fee|\n3|").err().unwrap();
+
+        assert_eq!(error, ParseError::DuplicateLabel { label: "fee".to_string(), first: (1, 1), second: (3, 1) });
+        assert_eq!(error.to_string(), "label 'fee' is defined twice, at A1 and A3");
+
+        let error = Spreadsheet::try_from_csv("!a,!a\n1,2").err().unwrap();
+        assert_eq!(error, ParseError::DuplicateLabel { label: "a".to_string(), first: (1, 1), second: (1, 2) });
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+        spreadsheet.set_cell(11, 1, r#"=lookup("adjusted_cost", 1)"#);
+        spreadsheet.set_cell(11, 2, r#"=lookup(concat("cost_", "threshold"), 1)"#);
+        spreadsheet.set_cell(11, 3, r#"=lookup("missing", 1)"#);
+
+        assert_eq!(spreadsheet.evaluate_cell(11, 1), spreadsheet.get_by_label("adjusted_cost", 1).unwrap());
+        assert_eq!(spreadsheet.evaluate_cell(11, 2), "10000");
+        assert_eq!(spreadsheet.evaluate_cell(11, 3), "#NAME?");
+
+        spreadsheet.set_cell(11, 1, r#"=lookup("fee", 1e30)"#);
+        assert_eq!(spreadsheet.evaluate_cell(11, 1), "#REF!");
+
+        for (offset, expected) in [("0-1", "#REF!"), ("1.7", "#VALUE!"), ("1e308*10", "#NUM!")] {
+            spreadsheet.set_cell(11, 1, &format!(r#"=lookup("fee", {})"#, offset));
+            assert_eq!(spreadsheet.evaluate_cell(11, 1), expected, "{}", offset);
+        }
+        assert_eq!(spreadsheet.get_by_label("fee", usize::MAX), None);
+    }
+
+    #[test]
+    fn test_iter_evaluated() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+
+        let mut rows = spreadsheet.iter_evaluated();
+        assert_eq!(rows.next().unwrap()[0], "date");
+        assert_eq!(rows.next().unwrap()[1], "t_1");
+
+        assert_eq!(spreadsheet.iter_evaluated().collect::<Vec<_>>(), spreadsheet.evaluate().spreadsheet);
+    }
+
+    #[test]
+    fn test_from_path() {
+        let path = std::env::temp_dir().join(format!("spreadsheet-test-{}.csv", std::process::id()));
+        std::fs::write(&path, include_str!("../transactions.csv")).unwrap();
+
+        let spreadsheet = Spreadsheet::from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let spreadsheet = spreadsheet.unwrap_or_else(|error| panic!("{}", error));
+        assert_eq!(spreadsheet.get_by_label("fee", 1), Some("0.09".to_string()));
+
+        assert!(matches!(Spreadsheet::from_path(&path), Err(LoadError::Io(_))));
+        assert!(matches!(Spreadsheet::from_reader("!a|!a".as_bytes()), Err(LoadError::Parse(_))));
+    }
+
+    #[test]
+    fn test_from_csv() {
+        let spreadsheet = Spreadsheet::from_csv("!name,!amount\n\"Smith, J\",10\n\"say \"\"hi\"\"\",=B2*2\n");
+        let evaluated = spreadsheet.evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![
+            vec!["name", "amount"],
+            vec!["Smith, J", "10"],
+            vec!["say \"hi\"", "20"],
+        ]);
+        assert_eq!(spreadsheet.get_by_label("amount", 2), Some("20".to_string()));
+    }
+
+    #[test]
+    fn test_from_tsv() {
+        let evaluated = Spreadsheet::from_tsv("a, b\t2\n9%\t=B1+1").evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![vec!["a, b", "2"], vec!["9%", "3"]]);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let evaluated = Spreadsheet::from_str("a|b|c\nlonger|=1+1").evaluate();
+
+        let transposed = evaluated.transpose();
+
+        assert_eq!(transposed.spreadsheet, vec![vec!["a", "longer"], vec!["b", "2"], vec!["c", ""]]);
+        assert_eq!(transposed.columns_length, HashMap::from([(0, 1), (1, 6)]));
+        assert_eq!(transposed.to_string(), "a | longer\nb | 2     \nc |       ");
+    }
+
+    #[test]
+    fn test_to_string_with_separator() {
+        let evaluated = Spreadsheet::from_str("a|=1+1\nlonger|3").evaluate();
+
+        assert_eq!(evaluated.to_string_with_separator("\t"), "a     \t2\nlonger\t3");
+        assert_eq!(evaluated.to_string_with_separator(","), "a     ,2\nlonger,3");
+        assert_eq!(evaluated.to_string(), "a      | 2\nlonger | 3");
+    }
+
+    #[test]
+    fn test_to_string_unpadded() {
+        let evaluated = Spreadsheet::from_str("a|=1+1\nlonger|3").evaluate();
+
+        assert_eq!(evaluated.to_string_with_separator(","), "a     ,2\nlonger,3");
+        assert_eq!(evaluated.to_string_unpadded(","), "a,2\nlonger,3");
+        assert_eq!(evaluated.to_string_unpadded(" | "), "a | 2\nlonger | 3");
+    }
+
+    #[test]
+    fn test_parse_sheet() {
+        let parsed = parse_sheet(include_str!("../transactions.csv")).unwrap();
+
+        assert_eq!(parsed.rows[0][0], Expression::Label("date".to_string()));
+        assert_eq!(parsed.rows[2][1], Expression::CopyAbove);
+        assert_eq!(
+            parsed.rows[1][4],
+            Expression::Function {
+                name: "sum".to_string(),
+                params: vec![Expression::Function {
+                    name: "spread".to_string(),
+                    params: vec![Expression::Function {
+                        name: "split".to_string(),
+                        params: vec![
+                            Expression::CellReference(CellReference {
+                                name: "D2".to_string(),
+                                column_name: "D".to_string(),
+                                column: 4,
+                                row: 2,
+                                absolute_column: false,
+                                absolute_row: false,
+                            }),
+                            Expression::String(",".to_string()),
+                        ],
+                    }],
+                }],
+            },
+        );
+        assert_eq!(parsed.labels["date"], (1, 1));
+        assert_eq!(parsed.labels["cost_threshold"], (5, 2));
+        assert!(matches!(parse_sheet("!a|!a"), Err(ParseError::DuplicateLabel { .. })));
+    }
+
+    #[test]
+    fn test_explain() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+
+        assert_eq!(
+            spreadsheet.explain(3, 5),
+            "+\n  copy evaluated E^\n  sum()\n    spread()\n      split()\n        reference D3\n        string \",\"",
+        );
+        assert_eq!(spreadsheet.explain(1, 1), "label !date");
+        assert_eq!(spreadsheet.explain(100, 1), "error #REF!");
+
+        let spreadsheet = Spreadsheet::from_str("=-(1+2)*@fee<1>^2");
+        assert_eq!(
+            spreadsheet.explain(1, 1),
+            "*\n  negate\n    +\n      number 1\n      number 2\n  ^\n    label reference @fee<1,0>\n    number 2",
+        );
+    }
+
+    #[test]
+    fn test_evaluate_topologically() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+        assert_eq!(spreadsheet.evaluate_topologically().unwrap().to_string(), spreadsheet.evaluate().to_string());
+
+        let spreadsheet = Spreadsheet::from_str("=B1*2|=C2+1|\n=A1|=^^|5");
+        assert_eq!(spreadsheet.evaluate_topologically().unwrap().to_string(), spreadsheet.evaluate().to_string());
+        assert_eq!(spreadsheet.evaluate_topologically().unwrap().spreadsheet, vec![vec!["12", "6", ""], vec!["12", "#REF!", "5"]]);
+    }
+
+    #[test]
+    fn test_evaluate_topologically_row_zero() {
+        let spreadsheet = Spreadsheet::from_str("1|2\n=sum(0:1)|=countblank(0:0)\n=weighted_average(0:1, 1:1)|");
+        let evaluated = spreadsheet.evaluate_topologically().unwrap();
+
+        assert_eq!(evaluated.spreadsheet[1], vec!["#REF!", "#REF!"]);
+        assert_eq!(evaluated.spreadsheet[2][0], "#REF!");
+    }
+
+    #[test]
+    fn test_evaluate_topologically_detects_cycles() {
+        let error = Spreadsheet::from_str("=B1+1|=A1\n=A1|=B2").evaluate_topologically().err().unwrap();
+
+        assert_eq!(error, SpreadsheetError::Cycle { cells: vec![(1, 1), (1, 2), (2, 2)] });
+        assert_eq!(error.to_string(), "circular reference between A1, B1, B2");
+    }
+
+    #[test]
+    fn test_with_locale() {
+        let input = "1.000,50|2,25|12,5%|=sum(A1, B1)|=A1*C1\n=D1+1|abc|1.00,5";
+
+        let mut spreadsheet = Spreadsheet::from_str(input).with_locale(Locale::EUROPEAN);
+        spreadsheet.set_cell(2, 4, "3,5");
+        assert_eq!(spreadsheet.evaluate().spreadsheet, vec![
+            vec!["1000.50", "2.25", "12.5%", "1002.75", "125.06"],
+            vec!["1003.75", "abc", "1.00,5", "3.50", ""],
+        ]);
+
+        let spreadsheet = Spreadsheet::from_str(input);
+        assert_eq!(spreadsheet.evaluate_cell(1, 4), "0");
+
+        let spreadsheet = Spreadsheet::from_str(r#"1,5;2,5|=sum(split(A1, ";"))|=sum(split("1.000,5;49,0%", ";"))|=abs(1.234)"#).with_locale(Locale::EUROPEAN);
+        assert_eq!(spreadsheet.evaluate().spreadsheet[0], vec!["1,5;2,5", "4", "1000.99", "1.23"]);
+        assert!(spreadsheet.evaluate().warnings().is_empty());
+    }
+
+    #[test]
+    fn test_warnings() {
+        let spreadsheet = Spreadsheet::from_str("!cost|abc\n5|=B1*2\n=A1+A2|=@cost<5>\n=A2+sum(C^v)+B3|=A^v");
+        let evaluated = spreadsheet.evaluate();
+
+        assert_eq!(evaluated.warnings(), &[
+            Warning { row: 2, column: 2, kind: WarningKind::TextAsNumber("abc".to_string()) },
+            Warning { row: 3, column: 1, kind: WarningKind::TextAsNumber("cost".to_string()) },
+            Warning { row: 3, column: 2, kind: WarningKind::LabelOffsetOutOfRange("cost".to_string()) },
+            Warning { row: 4, column: 1, kind: WarningKind::EmptyColumnReference("C".to_string()) },
+            Warning { row: 4, column: 1, kind: WarningKind::TextAsNumber("error".to_string()) },
+        ]);
+        assert_eq!(evaluated.warnings()[0].to_string(), "B2: text 'abc' was used as the number 0");
+        assert_eq!(spreadsheet.evaluate().warnings().len(), 5);
+        assert!(Spreadsheet::from_str(include_str!("../transactions.csv")).evaluate().warnings().is_empty());
+    }
+
+    #[test]
+    fn test_with_verbatim_strings() {
+        let evaluated = Spreadsheet::from_str("007|1.0|=1.0+6|=A1\n!id|0042|abc|").evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![vec!["7", "1", "7", "7"], vec!["id", "42", "abc", ""]]);
+        assert_eq!(evaluated.with_verbatim_strings().spreadsheet, vec![vec!["007", "1.0", "7", "007"], vec!["id", "0042", "abc", ""]]);
+        assert_eq!(evaluated.with_verbatim_strings().transpose().spreadsheet[1], vec!["1.0", "0042"]);
+        assert_eq!(evaluated.transpose().with_verbatim_strings().spreadsheet[1], vec!["1.0", "0042"]);
+    }
+
+    #[test]
+    fn test_verbatim_strings_follow_values() {
+        let input = "1e3|2022|=A1|=concat(\"20\", \"22\")|=B1+0|=A1*1|=text(5, \"0.00\")";
+        let evaluated = Spreadsheet::from_str(input).evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![vec!["1000", "2022", "1000", "2022", "2022", "1000", "5"]]);
+        assert_eq!(evaluated.with_verbatim_strings().spreadsheet, vec![vec!["1e3", "2022", "1e3", "2022", "2022", "1000", "5.00"]]);
+    }
+
+    #[test]
+    fn test_ragged_rows_are_padded() {
+        let mut spreadsheet = Spreadsheet::from_str("a|b|c\n=A^v\nd|e");
+        assert_eq!(spreadsheet.evaluate().spreadsheet, vec![vec!["a", "b", "c"], vec!["d", "", ""], vec!["d", "e", ""]]);
+        assert_eq!(spreadsheet.evaluate().to_string(), "a | b | c\nd |   |  \nd | e |  ");
+
+        spreadsheet.set_cell(2, 5, "x");
+        assert!(spreadsheet.rows.iter().all(|row| row.len() == 5));
+
+        let spreadsheet = Spreadsheet::builder().row(["1"]).row(["2", "3"]).build();
+        assert_eq!(spreadsheet.evaluate().spreadsheet, vec![vec!["1", ""], vec!["2", "3"]]);
+    }
+
+    #[test]
+    fn test_iter_cells() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+
+        let functions = spreadsheet
+            .iter_cells()
+            .filter(|(_, _, cell)| matches!(cell, Expression::Function { .. }))
+            .map(|(row, column, _)| (row, column))
+            .collect::<Vec<_>>();
+        assert_eq!(functions, vec![(2, 2), (2, 5)]);
+        assert_eq!(spreadsheet.iter_cells().count(), 50);
+        assert_eq!(spreadsheet.iter_cells().next(), Some((1, 1, &Expression::Label("date".to_string()))));
+    }
+
+    #[test]
+    fn test_dimensions() {
+        let mut spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+        assert_eq!(spreadsheet.dimensions(), (10, 5));
+
+        spreadsheet.set_cell(12, 7, "1");
+        assert_eq!(spreadsheet.row_count(), 12);
+        assert_eq!(spreadsheet.column_count(), 7);
+
+        assert_eq!(Spreadsheet::from_str("a\nb|c|d\ne|f").dimensions(), (3, 3));
+        assert_eq!(Spreadsheet::builder().build().dimensions(), (0, 0));
+    }
+
+    #[test]
+    fn test_column_names_round_trip() {
+        for index in 1..=1000 {
+            assert_eq!(column_index_from_name(&column_name_from_index(index)), index, "column {}", index);
+        }
+
+        assert_eq!(column_name_from_index(0), "");
+        assert_eq!(column_index_from_name(""), 0);
+        assert_eq!(checked_column_index_from_name("ZZZZZZZZZZZZZZZZ"), None);
+        assert!(matches!(Spreadsheet::try_from_str("=ZZZZZZZZZZZZZZZZ1"), Err(ParseError::Syntax(_))));
+        assert_eq!(column_name_from_index(26), "Z");
+        assert_eq!(column_name_from_index(27), "AA");
+        assert_eq!(column_name_from_index(52), "AZ");
+        assert_eq!(column_name_from_index(53), "BA");
+        assert_eq!(column_name_from_index(702), "ZZ");
+        assert_eq!(column_name_from_index(703), "AAA");
+    }
+
+    #[test]
+    fn test_multibyte_alignment() {
+        let evaluated = Spreadsheet::from_str("héllo|€5\nab|1").evaluate();
+
+        assert_eq!(evaluated.columns_length, HashMap::from([(0, 5), (1, 2)]));
+        assert_eq!(evaluated.to_string(), "héllo | €5\nab    | 1 ");
+    }
+
+    #[test]
+    fn test_to_value() {
+        let value = Spreadsheet::from_str("!name|!total\nfoo|=1+1").evaluate().to_value();
+
+        assert_eq!(value, SheetValue {
+            rows: vec![vec!["name".to_string(), "total".to_string()], vec!["foo".to_string(), "2".to_string()]],
+            column_widths: vec![4, 5],
+        });
+        assert_eq!(Spreadsheet::builder().build().evaluate().to_value().column_widths, Vec::<usize>::new());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_value_json() {
+        let value = Spreadsheet::from_str("!name|!total\nfoo|=1+1").evaluate().to_value();
+
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, r#"{"rows":[["name","total"],["foo","2"]],"column_widths":[4,5]}"#);
+        assert_eq!(serde_json::from_str::<SheetValue>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn test_register_function() {
+        let mut spreadsheet = Spreadsheet::from_str("4|=double(A1)|=Double(A1+1)*10|=double(spread(split(\"1,2\", \",\")))\n=abs(0-1)");
+        spreadsheet.register_function("double", |params| {
+            Expression::Number(params.iter().map(|param| param.as_number().unwrap_or(0.0)).sum::<f64>() * 2.0)
+        });
+        spreadsheet.register_function("ABS", |_| Expression::String("overridden".to_string()));
+
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[0], vec!["4", "8", "100", "6"]);
+        assert_eq!(evaluated.spreadsheet[1][0], "overridden");
+    }
+
+    #[test]
+    fn test_repeated_calls_are_computed_once_per_cell() {
+        let calls = Rc::new(Cell::new(0));
+        let mut spreadsheet = Spreadsheet::from_str("4|=costly(A1)+sum(costly(A1), 1)|=costly(A1)\n=costly(B2)*costly(B2)|3");
+        let counter = calls.clone();
+        spreadsheet.register_function("costly", move |params| {
+            counter.set(counter.get() + 1);
+            params[0].clone()
+        });
+
+        assert_eq!(spreadsheet.evaluate_cell(1, 2), "9");
+        assert_eq!(calls.get(), 1);
+        assert_eq!(spreadsheet.evaluate_cell(2, 1), "9");
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        let evaluated = spreadsheet.evaluate();
+        assert_eq!(evaluated.spreadsheet[0], vec!["4", "9", "4"]);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_calls_written_alike_are_cached_apart() {
+        let istext = |param| Expression::Function { name: "istext".to_string(), params: vec![param] };
+        let (error, text) = (istext(Expression::Error(CellError::Ref)), istext(Expression::String("#REF!".to_string())));
+        assert_eq!(error.to_formula(), text.to_formula());
+
+        let formula = Expression::Function { name: "concat".to_string(), params: vec![error, text] };
+        let spreadsheet = Spreadsheet::from_parts(vec![vec![formula]], LabelsMap::new());
+        assert_eq!(spreadsheet.evaluate().spreadsheet[0][0], "falsetrue");
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let input = "=A2\n=A3+1\n=A4\n=A5\n5";
+
+        assert_eq!(Spreadsheet::from_str(input).evaluate_cell(1, 1), "6");
+        assert_eq!(Spreadsheet::from_str(input).with_recursion_limit(5).evaluate_cell(1, 1), "6");
+    }
+
+    #[test]
+    fn test_recursion_limit_exceeded() {
+        let spreadsheet = Spreadsheet::from_str("=A2\n=A3+1\n=A4\n=A5\n5").with_recursion_limit(4);
+
+        assert_eq!(spreadsheet.evaluate_cell(1, 1), "#REF!");
+        assert_eq!(spreadsheet.try_evaluate().err(), Some(SpreadsheetError::RecursionLimit));
+    }
+
+    #[test]
+    fn test_self_reference_hits_recursion_limit() {
+        // Spawned threads get the smallest default stack, the default limit has to fit in it.
+        let errors = std::thread::spawn(|| {
+            ["=A1+1", "=abs(A1)", "=if(A1, 1, 2)", "=B1|=concat(A1, \"x\")"]
+                .map(|input| Spreadsheet::from_str(input).try_evaluate().err())
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(errors, [(); 4].map(|_| Some(SpreadsheetError::RecursionLimit)));
+    }
+
+    #[test]
+    fn test_try_evaluate() {
+        assert!(Spreadsheet::from_str(include_str!("../transactions.csv")).try_evaluate().is_ok());
+
+        let failures = [
+            ("=1/0", SpreadsheetError::DivisionByZero),
+            ("1|=A1/(A1-1)", SpreadsheetError::DivisionByZero),
+            ("=nope(1)", SpreadsheetError::UnknownFunction("nope".to_string())),
+            ("=map(split(\"a\", \",\"), \"nope\")", SpreadsheetError::UnknownFunction("nope".to_string())),
+            ("=abs(1, 2)", SpreadsheetError::WrongArity { function: "abs".to_string(), expected: "1".to_string(), got: 2 }),
+            ("=text(1, \"0.0.0\")", SpreadsheetError::InvalidValue("unsupported number format '0.0.0'".to_string())),
+            ("=A1", SpreadsheetError::RecursionLimit),
+            ("1|=Z99", SpreadsheetError::InvalidReference("Z99".to_string())),
+            ("=@missing<1>", SpreadsheetError::UnknownLabel("missing".to_string())),
+            ("!a\n=@a<9>", SpreadsheetError::InvalidReference("@a<9>".to_string())),
+            ("=^^", SpreadsheetError::InvalidReference("^^".to_string())),
+        ];
+        for (input, expected) in failures {
+            let spreadsheet = Spreadsheet::from_str(input);
+            assert_eq!(spreadsheet.try_evaluate().err(), Some(expected), "{}", input);
+        }
+
+        let evaluated = Spreadsheet::from_str("=1/0|=nope()|=abs()").evaluate();
+        assert_eq!(evaluated.spreadsheet[0], vec!["#DIV/0!", "#NAME?", "#VALUE!"]);
+        assert_eq!(Spreadsheet::from_str("1|=Z99").evaluate().diagnostics(), "#REF! at B1: Z99 doesn't refer to a cell");
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let input = "=sum(split(\"1,foo,3\", \",\"))|=A2*2|=sum(split(\"1,,3\", \",\"))\nbar|=isblank(\"\")+1|=lower(\"ok\")";
+
+        let lenient = Spreadsheet::from_str(input).evaluate();
+        assert_eq!(lenient.spreadsheet[0], vec!["4", "0", "4"]);
+        assert_eq!(lenient.warnings().len(), 2);
+
+        let strict = Spreadsheet::from_str(input).with_strict_mode();
+        assert_eq!(strict.try_evaluate().err(), Some(SpreadsheetError::TextAsNumber("foo".to_string())));
+        let evaluated = strict.evaluate();
+        assert_eq!(evaluated.spreadsheet[0], vec!["#VALUE!", "#VALUE!", "4"]);
+        assert_eq!(evaluated.spreadsheet[1], vec!["bar", "2", "ok"]);
+        assert!(evaluated.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_errors_with_coordinates() {
+        let evaluated = Spreadsheet::from_str("1|2\n=A1+1|=B1/0\n=nope(A2)|=A2").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[1], vec!["2", "#DIV/0!"]);
+        assert_eq!(evaluated.spreadsheet[2], vec!["#NAME?", "2"]);
+        assert_eq!(evaluated.errors(), vec![
+            (2, 2, SpreadsheetError::DivisionByZero),
+            (3, 1, SpreadsheetError::UnknownFunction("nope".to_string())),
+        ]);
+        assert_eq!(evaluated.diagnostics(), "#DIV/0! at B2: division by zero\n#NAME? at A3: unknown function 'nope'");
+        assert_eq!(evaluated.transpose().errors()[0], (2, 2, SpreadsheetError::DivisionByZero));
+        assert_eq!(evaluated.transpose().errors()[1], (1, 3, SpreadsheetError::UnknownFunction("nope".to_string())));
+
+        assert!(Spreadsheet::from_str("1|2").evaluate().errors().is_empty());
+    }
+
+    #[test]
+    fn test_multi_line_cell_rendering() {
+        let evaluated = Spreadsheet::from_str("\"two\nlines\"|x\n=upper(A1)|y").evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0][0], "two\nlines");
+        assert_eq!(evaluated.to_string(), "two\\nlines | x\nTWO\\nLINES | y");
+    }
+
+    #[test]
+    fn test_to_source_round_trip() {
+        let mut spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+        spreadsheet.set_cell(11, 1, r#"=concat("say \"hi\"", "\t", A2)"#);
+        spreadsheet.set_cell(11, 2, "two words");
+        spreadsheet.set_cell(11, 3, "\"9%\"");
+        spreadsheet.set_cell(11, 4, "=-(1+2)*3^(4-1)^2/(5/6)-B$1");
+
+        let source = spreadsheet.to_source();
+        let reparsed = Spreadsheet::from_str(&source);
+        assert_eq!(reparsed.rows, spreadsheet.rows);
+        assert_eq!(reparsed.labels_map, spreadsheet.labels_map);
+        assert_eq!(reparsed.to_source(), source);
+        assert_eq!(source.lines().next(), Some("!date|!transaction_id|!tokens|!token_prices|!total_cost"));
+        assert!(source.contains("|=E^+sum(spread(split(D3, \",\")))\n"));
+    }
+
+    #[test]
+    fn test_headers() {
+        let spreadsheet = Spreadsheet::from_str(include_str!("../transactions.csv"));
+
+        assert_eq!(
+            spreadsheet.headers(),
+            Some(vec!["date", "transaction_id", "tokens", "token_prices", "total_cost"].into_iter().map(String::from).collect()),
+        );
+        assert_eq!(spreadsheet.get_by_header("total_cost", 2), Some("40986.65".to_string()));
+        assert_eq!(spreadsheet.get_by_header("transaction_id", 3), Some("t_2".to_string()));
+        assert_eq!(spreadsheet.get_by_header("total_cost", 20), None);
+        assert_eq!(spreadsheet.get_by_header("fee", 6), None);
+
+        assert_eq!(Spreadsheet::from_str("!a|b\n1|2").headers(), None);
+        assert_eq!(Spreadsheet::from_str("!a|b\n1|2").get_by_header("a", 2), None);
+    }
+
+    #[test]
+    fn test_blank_lines_keep_references_stable() {
+        let input = "1|2\n\n=A1+B1|=A3*10\n5|=A4";
+
+        let evaluated = Spreadsheet::from_str(input).evaluate();
+        assert_eq!(evaluated.spreadsheet, vec![vec!["1", "2"], vec!["", ""], vec!["3", "30"], vec!["5", "5"]]);
+
+        let skipped = Spreadsheet::try_from_str_with_options(input, ParseOptions { skip_blank_lines: true }).unwrap().evaluate();
+        assert_eq!(skipped.spreadsheet, vec![vec!["1", "2"], vec!["3", "50"], vec!["5", "#REF!"]]);
+    }
+
+    #[test]
+    fn test_to_html() {
+        let evaluated = Spreadsheet::from_str("!name|!total\n\"<script>alert(\\\"x\\\")</script>\"|=2*3\nAT&T|").evaluate();
+
+        assert_eq!(evaluated.to_html(), [
+            "<table>",
+            "<tr><th>name</th><th>total</th></tr>",
+            "<tr><td>&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;</td><td>6</td></tr>",
+            "<tr><td>AT&amp;T</td><td></td></tr>",
+            "</table>",
+        ].join("\n"));
+
+        let evaluated = Spreadsheet::from_str("!a|b\n1|2").evaluate();
+        assert!(evaluated.to_html().starts_with("<table>\n<tr><td>a</td><td>b</td></tr>"));
+        assert!(!evaluated.transpose().to_html().contains("<th>"));
+    }
+
+    #[test]
+    fn test_to_tsv() {
+        let evaluated = Spreadsheet::from_str("=concat(\"a\\tb\")|=1+2\n\"two\nlines\"|").evaluate();
+
+        assert_eq!(evaluated.to_tsv(), "a b\t3\ntwo lines\t");
+    }
+
+    #[test]
+    fn test_errors_propagate_through_arithmetic() {
+        let spreadsheet = Spreadsheet::from_str("1|=1/0|3\n=sum(1:1)|=B1*2+1|=-B1\n=A1+@missing<1>|=vlookup(9, A^v, B^v)^2|=A1+C2");
+        let evaluated = spreadsheet.evaluate();
+
+        assert_eq!(evaluated.spreadsheet, vec![
+            vec!["1", "#DIV/0!", "3"],
+            vec!["#DIV/0!", "#DIV/0!", "#DIV/0!"],
+            vec!["#NAME?", "#N/A", "#DIV/0!"],
+        ]);
+        assert_eq!(evaluated.errors(), vec![(1, 2, SpreadsheetError::DivisionByZero), (3, 1, SpreadsheetError::UnknownLabel("missing".to_string()))]);
+    }
+
+    #[test]
+    fn test_pipe() {
+        let evaluated = Spreadsheet::from_str(r#"1,2,3|=split(A1, ",") |> sum|=B1 |> repeat(2) |> concat(" total")"#).evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0], vec!["1,2,3", "6", "66 total"]);
+    }
+
+    #[test]
+    fn test_try_from_str_rejects_out_of_range_references() {
+        assert!(Spreadsheet::try_from_str("=A99999999999999999999999").is_err());
+        assert!(Spreadsheet::try_from_str("=A99999").is_ok());
+    }
+
+    #[test]
+    fn test_escaped_tab_in_formula() {
+        let evaluated = Spreadsheet::from_str(r#"=concat("a\tb")"#).evaluate();
+
+        assert_eq!(evaluated.spreadsheet[0][0], "a\tb");
+    }
 }
@@ -0,0 +1,71 @@
+/// How numbers are written in the literal cells of a sheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    pub decimal_separator: char,
+    /// `None` when numbers are written without grouping their thousands.
+    pub thousands_separator: Option<char>,
+}
+
+impl Locale {
+    /// `1234.5`, the way Rust writes numbers. This is the default.
+    pub const DEFAULT: Locale = Locale { decimal_separator: '.', thousands_separator: None };
+    /// `1,234.5`
+    pub const US: Locale = Locale { decimal_separator: '.', thousands_separator: Some(',') };
+    /// `1.234,5`
+    pub const EUROPEAN: Locale = Locale { decimal_separator: ',', thousands_separator: Some('.') };
+
+    /// Parses a number written in this locale. Thousands separators are optional, but when used
+    /// every group after the first one must have three digits.
+    pub(crate) fn parse_number(&self, text: &str) -> Option<f64> {
+        let (integer, fraction) = match text.split_once(self.decimal_separator) {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (text, None),
+        };
+
+        let mut normalized = match self.thousands_separator {
+            Some(separator) if integer.contains(separator) => {
+                let mut groups = integer.split(separator);
+                let first = groups.next().unwrap_or_default();
+                let rest = groups.collect::<Vec<_>>();
+                if rest.iter().any(|group| group.len() != 3) {
+                    return None;
+                }
+                format!("{}{}", first, rest.concat())
+            }
+            _ => integer.to_string(),
+        };
+        if let Some(fraction) = fraction {
+            normalized.push('.');
+            normalized.push_str(fraction);
+        }
+
+        // Rust also accepts words like "inf", which aren't numbers in any locale.
+        if !normalized.trim_start_matches(['-', '+']).starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            return None;
+        }
+        normalized.parse().ok()
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(Locale::EUROPEAN.parse_number("1.000,50"), Some(1000.5));
+        assert_eq!(Locale::EUROPEAN.parse_number("-2,25"), Some(-2.25));
+        assert_eq!(Locale::EUROPEAN.parse_number("1234"), Some(1234.0));
+        assert_eq!(Locale::EUROPEAN.parse_number("1.00,5"), None);
+        assert_eq!(Locale::US.parse_number("1,234,567.5"), Some(1234567.5));
+        assert_eq!(Locale::DEFAULT.parse_number("1,5"), None);
+        assert_eq!(Locale::DEFAULT.parse_number("inf"), None);
+        assert_eq!(Locale::EUROPEAN.parse_number("abc"), None);
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
-use pest::{Parser, iterators::Pair};
-use crate::column_index_from_name;
-use crate::expression::{CellReference, ColumnReference, Expression, LabelReference};
+use std::sync::LazyLock;
+use pest::{Parser, iterators::{Pair, Pairs}};
+use pest::error::ErrorVariant;
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+use crate::{checked_column_index_from_name, column_index_from_name};
+use crate::error::ParseError;
+use crate::expression::{CellReference, ColumnReference, Expression, LabelReference, RowReference};
 
 #[derive(Parser)]
 #[grammar = "spreadsheet.pest"]
@@ -11,13 +15,44 @@ pub(crate) type Sheet = Vec<Vec<Expression>>;
 
 pub(crate) type LabelsMap = HashMap<String, (usize, usize)>;
 
-pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), pest::error::Error<Rule>> {
+/// A sheet as it was written, before anything is evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedSheet {
+    pub rows: Vec<Vec<Expression>>,
+    /// Where each label is defined, as 1-based `(row, column)` positions.
+    pub labels: HashMap<String, (usize, usize)>,
+}
+
+/// Choices about how a sheet is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Drops blank lines instead of reading each of them as an empty row. Off by default, so that
+    /// adding a blank line doesn't shift the rows below it and every reference stays correct.
+    pub skip_blank_lines: bool,
+}
+
+/// Parses a sheet without evaluating it, for tooling that wants to inspect the formulas.
+pub fn parse_sheet(input: &str) -> Result<ParsedSheet, ParseError> {
+    let (rows, labels_map) = parse(input.trim())?;
+    let labels = labels_map
+        .into_iter()
+        .map(|(label, (row_index, column_index))| (label, (row_index + 1, column_index + 1)))
+        .collect();
+    Ok(ParsedSheet { rows, labels })
+}
+
+pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), ParseError> {
+    parse_with_options(input, ParseOptions::default())
+}
+
+pub(crate) fn parse_with_options(input: &str, options: ParseOptions) -> Result<(Sheet, LabelsMap), ParseError> {
     let mut row_number = 0;
     let mut column_number;
     let mut rows: Sheet = vec![];
     let mut labels_map: LabelsMap = HashMap::new();
 
     let pairs = SpreadsheetParser::parse(Rule::file, input)?;
+    check_naturals(&pairs)?;
 
     for pair in pairs {
         let rule = pair.as_rule();
@@ -40,6 +75,8 @@ pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), pest::error::Erro
                                             current_cell = Some(new_cell)
                                         }
                                     }
+                                    // Both end the current cell, so `a|b|` ends with an empty
+                                    // third cell.
                                     Rule::delimiter | Rule::end_of_line => {
                                         let expr = if let Some(content) = &current_cell {
                                             content.clone()
@@ -48,7 +85,13 @@ pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), pest::error::Erro
                                         };
 
                                         if let Expression::Label(label) = &expr {
-                                            labels_map.insert(label.to_string(), (row_number, column_number));
+                                            if let Some((first_row, first_column)) = labels_map.insert(label.to_string(), (row_number, column_number)) {
+                                                return Err(ParseError::DuplicateLabel {
+                                                    label: label.to_string(),
+                                                    first: (first_row + 1, first_column + 1),
+                                                    second: (row_number + 1, column_number + 1),
+                                                });
+                                            }
                                         }
 
                                         cells.push(expr);
@@ -62,6 +105,11 @@ pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), pest::error::Erro
                             row_number += 1;
                             rows.push(cells);
                         }
+                        Rule::blank_line if !options.skip_blank_lines => {
+                            row_number += 1;
+                            rows.push(vec![]);
+                        }
+                        Rule::blank_line | Rule::comment => {}
                         _ => unreachable!(),
                     }
                 }
@@ -69,133 +117,204 @@ pub(crate) fn parse(input: &str) -> Result<(Sheet, LabelsMap), pest::error::Erro
             _ => unreachable!()
         }
     }
-    return Ok((rows, labels_map));
+    pad_rows(&mut rows);
+    Ok((rows, labels_map))
+}
+
+/// Pads every row with empty cells up to the length of the longest one, so the sheet is always
+/// rectangular.
+pub(crate) fn pad_rows(rows: &mut Sheet) {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    for row in rows {
+        row.resize(columns, Expression::Empty);
+    }
 }
 
 pub(crate) fn parse_cell_from_str(input: &str) -> Option<Expression> {
-    let pairs = SpreadsheetParser::parse(Rule::cell, input).unwrap();
+    if input.trim().is_empty() {
+        return None;
+    }
 
-    for pair in pairs {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::cell => {
-                return parse_cell(pair);
-            }
-            _ => unreachable!()
-        };
+    let pairs = SpreadsheetParser::parse(Rule::cell, input).ok()?;
+    check_naturals(&pairs).ok()?;
+    let pair = pairs.into_iter().next()?;
+
+    match pair.as_rule() {
+        Rule::cell => parse_cell(pair),
+        _ => unreachable!()
     }
-    None
 }
 
-fn parse_cell(pair: Pair<Rule>) -> Option<Expression> {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        return match rule {
-            Rule::label => {
-                Some(parse_label(pair))
-            }
-            Rule::equation => {
-                Some(parse_inner(pair))
+/// Row numbers, offsets and column names of references have to fit in a `usize`, which the
+/// grammar can't tell. Checking them up front lets the rest of the parser read them without failing.
+fn check_naturals(pairs: &Pairs<Rule>) -> Result<(), ParseError> {
+    for pair in pairs.clone().flatten() {
+        let message = match pair.as_rule() {
+            Rule::natural if pair.as_str().parse::<usize>().is_err() => {
+                format!("{} is too large for a row number or offset", pair.as_str())
             }
-            Rule::any_string => {
-                Some(Expression::String(pair.as_str().to_string()))
+            Rule::column if checked_column_index_from_name(pair.as_str()).is_none() => {
+                format!("{} is too large for a column name", pair.as_str())
             }
-            _ => None
+            _ => continue,
         };
+        return Err(pest::error::Error::new_from_span(ErrorVariant::CustomError { message }, pair.as_span()).into());
+    }
+    Ok(())
+}
+
+fn parse_cell(pair: Pair<Rule>) -> Option<Expression> {
+    let pair = pair.into_inner().next()?;
+    match pair.as_rule() {
+        Rule::label => {
+            Some(parse_label(pair))
+        }
+        Rule::equation => {
+            Some(parse_inner(pair))
+        }
+        Rule::quoted_string => {
+            Some(Expression::String(parse_string(pair)))
+        }
+        Rule::percentage => {
+            Some(parse_percentage(pair))
+        }
+        Rule::any_string => {
+            Some(Expression::String(pair.as_str().to_string()))
+        }
+        _ => None
     }
-    return None;
 }
 
 fn parse_label(pair: Pair<Rule>) -> Expression {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::identifier => {
-                return Expression::Label(pair.as_str().to_string());
-            }
-            _ => unreachable!()
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::identifier => {
+            Expression::Label(pair.as_str().to_string())
         }
+        _ => unreachable!()
     }
-    unreachable!()
 }
 
 fn parse_inner(pair: Pair<Rule>) -> Expression {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::expression => {
-                return parse_expression(pair);
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::expression => {
+            parse_expression(pair)
+        }
+        _ => unreachable!()
+    }
+}
+
+/// Reads the content of a double-quoted string, resolving its escape sequences.
+fn parse_string(pair: Pair<Rule>) -> String {
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::inner => {
+            unescape(pair.as_str())
+        }
+        _ => unreachable!()
+    }
+}
+
+/// Resolves `\n`, `\t`, `\"` and `\\` escapes. Unknown escape sequences are kept as written,
+/// so `"\d"` stays a backslash followed by `d`.
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(escaped @ ('"' | '\\')) => output.push(escaped),
+            Some(other) => {
+                output.push('\\');
+                output.push(other);
             }
-            _ => unreachable!()
+            None => output.push('\\'),
         }
     }
-    unreachable!()
+
+    output
 }
 
+/// Binds `^` tightest, then `*` and `/`, then `+` and `-`. Exponentiation is right-associative,
+/// everything else is left-associative.
+static PRATT_PARSER: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    PrattParser::new()
+        .op(Op::infix(Rule::plus, Assoc::Left) | Op::infix(Rule::minus, Assoc::Left))
+        .op(Op::infix(Rule::multiply, Assoc::Left) | Op::infix(Rule::divide, Assoc::Left))
+        .op(Op::infix(Rule::power, Assoc::Right))
+});
+
 fn parse_expression(pair: Pair<Rule>) -> Expression {
-    let mut params: Vec<Expression> = vec![];
-    let mut op = None;
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
+    let pairs = pair.into_inner();
+    let operation = parse_operation(pairs.clone().take_while(|pair| pair.as_rule() != Rule::pipe));
+    pairs.filter(|pair| pair.as_rule() == Rule::pipe).fold(operation, |piped, pipe| {
+        let stage = pipe.into_inner().next().unwrap();
+        match stage.as_rule() {
             Rule::function_call => {
-                let (function_name, function_params) = parse_function_call(pair);
-                params.push(Expression::Function { name: function_name, params: function_params });
-            }
-            Rule::reference => {
-                params.push(parse_reference(pair));
-            }
-            Rule::paren => {
-                params.push(parse_inner(pair));
+                let (function_name, mut function_params) = parse_function_call(stage);
+                function_params.insert(0, piped);
+                Expression::Function { name: function_name, params: function_params }
             }
-            Rule::copy_evaluated => {
-                params.push(Expression::CopyEvaluated(parse_copy_evaluated(pair)));
-            }
-            Rule::copy_above => {
-                params.push(Expression::CopyAbove);
-            }
-            Rule::label_reference => {
-                let (label, row) = parse_label_reference(pair);
-                params.push(Expression::LabelReference(LabelReference { label, n_rows: row }));
-            }
-            Rule::value => {
-                params.push(parse_value(pair));
-            }
-            Rule::operator => {
-                op = Some(parse_operator(pair));
-            }
-            Rule::expression => {
-                params.push(parse_expression(pair));
-            }
-            _ => unreachable!()
+            _ => Expression::Function { name: stage.as_str().to_string(), params: vec![piped] },
         }
-        if op.is_some() && params.len() == 2 {
-            let rhs = params.pop().unwrap();
-            let lhs = params.pop().unwrap();
-            let param = match &op {
-                Some(Operator::Plus) => {
-                    Expression::Plus { args: vec![lhs, rhs] }
-                }
-                Some(Operator::Minus) => {
-                    Expression::Minus { args: vec![lhs, rhs] }
-                }
-                Some(Operator::Multiply) => {
-                    Expression::Multiply { args: vec![lhs, rhs] }
-                }
-                Some(Operator::Divide) => {
-                    Expression::Divide { args: vec![lhs, rhs] }
-                }
+    })
+}
+
+fn parse_operation<'i>(pairs: impl Iterator<Item = Pair<'i, Rule>>) -> Expression {
+    PRATT_PARSER
+        .map_primary(parse_operand)
+        .map_infix(|lhs, op, rhs| {
+            let args = vec![lhs, rhs];
+            match op.as_rule() {
+                Rule::plus => Expression::Plus { args },
+                Rule::minus => Expression::Minus { args },
+                Rule::multiply => Expression::Multiply { args },
+                Rule::divide => Expression::Divide { args },
+                Rule::power => Expression::Power { args },
                 _ => unreachable!()
-            };
-            params.push(param)
-        }
-    }
+            }
+        })
+        .parse(pairs)
+}
 
-    if let Some(value) = params.pop() {
-        return value;
+fn parse_operand(pair: Pair<Rule>) -> Expression {
+    let rule = pair.as_rule();
+    match rule {
+        Rule::function_call => {
+            let (function_name, function_params) = parse_function_call(pair);
+            Expression::Function { name: function_name, params: function_params }
+        }
+        Rule::reference => {
+            parse_reference(pair)
+        }
+        Rule::paren => {
+            parse_inner(pair)
+        }
+        Rule::copy_evaluated => {
+            Expression::CopyEvaluated(parse_copy_evaluated(pair))
+        }
+        Rule::copy_above => {
+            Expression::CopyAbove
+        }
+        Rule::label_reference => {
+            let (label, row, column) = parse_label_reference(pair);
+            Expression::LabelReference(LabelReference { label, n_rows: row, n_columns: column })
+        }
+        Rule::value => {
+            parse_value(pair)
+        }
+        Rule::negate => {
+            Expression::Negate(Box::new(parse_operand(pair.into_inner().next().unwrap())))
+        }
+        _ => unreachable!()
     }
-
-    unreachable!()
 }
 
 fn parse_function_call(pair: Pair<Rule>) -> (String, Vec<Expression>) {
@@ -214,36 +333,44 @@ fn parse_function_call(pair: Pair<Rule>) -> (String, Vec<Expression>) {
             _ => unreachable!()
         }
     }
-    return (function_name, function_params);
+    (function_name, function_params)
 }
 
 fn parse_reference(pair: Pair<Rule>) -> Expression {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::cell_reference => {
-                return Expression::CellReference(parse_cell_reference(pair));
-            }
-            Rule::column_reference => {
-                return Expression::ColumnReference(parse_column_reference(pair));
-            }
-            _ => unreachable!()
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::cell_reference => {
+            Expression::CellReference(parse_cell_reference(pair))
         }
+        Rule::column_reference => {
+            Expression::ColumnReference(parse_column_reference(pair))
+        }
+        Rule::row_reference => {
+            Expression::RowReference(parse_row_reference(pair))
+        }
+        _ => unreachable!()
     }
-    unreachable!()
 }
 
 fn parse_cell_reference(pair: Pair<Rule>) -> CellReference {
     let mut column_name = String::new();
     let mut row_number: usize = 0;
+    let mut absolute_column = false;
+    let mut absolute_row = false;
     for pair in pair.clone().into_inner() {
         let rule = pair.as_rule();
         match rule {
+            Rule::absolute_column => {
+                absolute_column = true;
+            }
+            Rule::absolute_row => {
+                absolute_row = true;
+            }
             Rule::column => {
                 column_name = pair.as_str().to_string();
             }
-            Rule::integer => {
-                row_number = pair.as_str().parse().expect("row number should be integer");
+            Rule::natural => {
+                row_number = pair.as_str().parse().expect("row numbers fit, see check_naturals");
             }
             _ => unreachable!()
         }
@@ -254,100 +381,364 @@ fn parse_cell_reference(pair: Pair<Rule>) -> CellReference {
         column_name: column_name.to_string(),
         column: column_index_from_name(&column_name),
         row: row_number,
+        absolute_column,
+        absolute_row,
     }
 }
 
 fn parse_column_reference(pair: Pair<Rule>) -> ColumnReference {
-    for pair in pair.clone().into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::column => {
-                return ColumnReference {
-                    name: pair.as_str().to_string(),
-                    column: column_index_from_name(pair.as_str()),
-                };
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::column => {
+            ColumnReference {
+                name: pair.as_str().to_string(),
+                column: column_index_from_name(pair.as_str()),
             }
-            _ => unreachable!()
         }
+        _ => unreachable!()
     }
-    unreachable!()
+}
+
+fn parse_row_reference(pair: Pair<Rule>) -> RowReference {
+    let name = pair.as_str().to_string();
+    let rows = pair
+        .into_inner()
+        .map(|pair| pair.as_str().parse().expect("row numbers fit, see check_naturals"))
+        .collect::<Vec<usize>>();
+    RowReference { name, first: rows[0].min(rows[1]), last: rows[0].max(rows[1]) }
 }
 
 fn parse_copy_evaluated(pair: Pair<Rule>) -> ColumnReference {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::column => {
-                let name = pair.as_str().to_string();
-                let column = column_index_from_name(pair.as_str());
-                return ColumnReference { name, column };
-            }
-            _ => unreachable!()
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::column => {
+            let name = pair.as_str().to_string();
+            let column = column_index_from_name(pair.as_str());
+            ColumnReference { name, column }
         }
+        _ => unreachable!()
     }
-    unreachable!()
 }
 
-fn parse_label_reference(pair: Pair<Rule>) -> (String, usize) {
+fn parse_label_reference(pair: Pair<Rule>) -> (String, usize, usize) {
     let mut label = String::new();
-    let mut row = 0;
+    let mut offsets = vec![];
     for pair in pair.into_inner() {
         let rule = pair.as_rule();
         match rule {
             Rule::identifier => {
                 label = pair.as_str().to_string();
             }
-            Rule::integer => {
-                row = pair.as_str().to_string().parse().expect("row number should be an integer");
+            Rule::natural => {
+                offsets.push(pair.as_str().parse().expect("label offsets fit, see check_naturals"));
             }
             _ => unreachable!()
         }
     }
-    (label, row)
+    // The column offset is optional, `@label<n>` stays in the labeled column.
+    (label, offsets[0], offsets.get(1).copied().unwrap_or(0))
 }
 
 fn parse_value(pair: Pair<Rule>) -> Expression {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::string => {
-                for pair in pair.into_inner() {
-                    let rule = pair.as_rule();
-                    return match rule {
-                        Rule::inner => {
-                            Expression::String(pair.as_str().to_string())
-                        }
-                        _ => unreachable!()
-                    };
-                }
-            }
-            Rule::float | Rule::integer => {
-                return Expression::Number(pair.as_str().to_string().parse().expect("expected number"));
-            }
-            _ => unreachable!()
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::string => {
+            Expression::String(parse_string(pair))
+        }
+        Rule::percentage => {
+            parse_percentage(pair)
         }
+        Rule::float | Rule::integer => {
+            Expression::Number(parse_number(pair.as_str()))
+        }
+        // Booleans are the same text comparisons produce, which arithmetic reads as 1 and 0.
+        Rule::boolean => {
+            Expression::String(pair.as_str().to_lowercase())
+        }
+        _ => unreachable!()
     }
-    unreachable!()
 }
 
-#[derive(Debug)]
-enum Operator {
-    Plus,
-    Minus,
-    Multiply,
-    Divide,
+fn parse_percentage(pair: Pair<Rule>) -> Expression {
+    let percent = pair.as_str().trim_end_matches('%');
+    Expression::Percentage(parse_number(percent))
 }
 
-fn parse_operator(pair: Pair<Rule>) -> Operator {
-    for pair in pair.into_inner() {
-        let rule = pair.as_rule();
-        match rule {
-            Rule::plus => return Operator::Plus,
-            Rule::minus => return Operator::Minus,
-            Rule::multiply => return Operator::Multiply,
-            Rule::divide => return Operator::Divide,
-            _ => unreachable!()
+/// Parses a number matched by the grammar, dropping the underscores between its digits.
+fn parse_number(text: &str) -> f64 {
+    text.replace('_', "").parse().expect("expected number")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quoted_cell_with_delimiter() {
+        let (rows, _) = parse(r#"a|"b|c"|d"#).unwrap();
+
+        assert_eq!(rows[0].len(), 3);
+        assert_eq!(rows[0][0], Expression::String("a".to_string()));
+        assert_eq!(rows[0][1], Expression::String("b|c".to_string()));
+        assert_eq!(rows[0][2], Expression::String("d".to_string()));
+    }
+
+    #[test]
+    fn test_quoted_text_followed_by_more_text() {
+        let (rows, _) = parse(r#""abc"def|x|"ab" |"#).unwrap();
+
+        assert_eq!(rows[0], vec![
+            Expression::String(r#""abc"def"#.to_string()),
+            Expression::String("x".to_string()),
+            Expression::String("ab".to_string()),
+            Expression::Empty,
+        ]);
+    }
+
+    #[test]
+    fn test_multi_line_quoted_cell() {
+        let (rows, _) = parse("a|\"first\nsecond\r\n# third\"|b\nc|d|e").unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], vec![
+            Expression::String("a".to_string()),
+            Expression::String("first\nsecond\r\n# third".to_string()),
+            Expression::String("b".to_string()),
+        ]);
+        assert_eq!(rows[1][0], Expression::String("c".to_string()));
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let (rows, _) = parse_with_options("!a|b\r\n1|=A2+1\r\n\r\n# note\r\n\"x\"|2\n3|4\r\n", ParseOptions { skip_blank_lines: true }).unwrap();
+
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], vec![Expression::Label("a".to_string()), Expression::String("b".to_string())]);
+        assert_eq!(rows[1][0], Expression::String("1".to_string()));
+        assert!(matches!(rows[1][1], Expression::Plus { .. }));
+        assert_eq!(rows[2], vec![Expression::String("x".to_string()), Expression::String("2".to_string())]);
+        assert_eq!(rows[3], vec![Expression::String("3".to_string()), Expression::String("4".to_string())]);
+    }
+
+    #[test]
+    fn test_trailing_delimiter() {
+        let cells = |input| parse(input).unwrap().0.remove(0);
+
+        assert_eq!(cells("a|b|"), vec![
+            Expression::String("a".to_string()),
+            Expression::String("b".to_string()),
+            Expression::Empty,
+        ]);
+        assert_eq!(cells("a|b").len(), 2);
+        assert_eq!(cells("a|b|\n").len(), 3);
+        assert_eq!(cells("a|b||"), vec![
+            Expression::String("a".to_string()),
+            Expression::String("b".to_string()),
+            Expression::Empty,
+            Expression::Empty,
+        ]);
+        assert_eq!(cells("|"), vec![Expression::Empty, Expression::Empty]);
+    }
+
+    #[test]
+    fn test_blank_lines() {
+        let input = "!a|b\n1|2\n\n\n3|=A5\n# note\n\n4|5";
+
+        let (rows, labels) = parse(input).unwrap();
+        assert_eq!(rows.len(), 7);
+        assert_eq!(rows[2], vec![Expression::Empty, Expression::Empty]);
+        assert_eq!(rows[3], vec![Expression::Empty, Expression::Empty]);
+        assert_eq!(rows[4][0], Expression::String("3".to_string()));
+        assert_eq!(rows[5], vec![Expression::Empty, Expression::Empty]);
+        assert_eq!(rows[6][1], Expression::String("5".to_string()));
+        assert_eq!(labels["a"], (0, 0));
+
+        let (rows, _) = parse_with_options(input, ParseOptions { skip_blank_lines: true }).unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[2][0], Expression::String("3".to_string()));
+        assert_eq!(rows[3][0], Expression::String("4".to_string()));
+    }
+
+    #[test]
+    fn test_quoted_cell_with_escapes() {
+        let (rows, _) = parse(r#""say \"hi\""|"back\\slash""#).unwrap();
+
+        assert_eq!(rows[0][0], Expression::String(r#"say "hi""#.to_string()));
+        assert_eq!(rows[0][1], Expression::String(r"back\slash".to_string()));
+    }
+
+    #[test]
+    fn test_comment_lines_are_skipped() {
+        let (rows, _) = parse("# heading\na|b\n# note\n\"# not a comment\"|c\n").unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][0], Expression::String("a".to_string()));
+        assert_eq!(rows[1][0], Expression::String("# not a comment".to_string()));
+    }
+
+    #[test]
+    fn test_string_literal_escapes() {
+        let expr = parse_cell_from_str(r#"=concat("a\tb", "\n", "\"\\", "\d")"#).unwrap();
+
+        let Expression::Function { params, .. } = expr else { panic!("expected a function call") };
+        assert_eq!(params, vec![
+            Expression::String("a\tb".to_string()),
+            Expression::String("\n".to_string()),
+            Expression::String("\"\\".to_string()),
+            Expression::String("\\d".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_percentage() {
+        let (rows, _) = parse("9%|9%x|=12.5%*2").unwrap();
+
+        assert_eq!(rows[0][0], Expression::Percentage(9.0));
+        assert_eq!(rows[0][1], Expression::String("9%x".to_string()));
+        assert_eq!(rows[0][2], Expression::Multiply { args: vec![Expression::Percentage(12.5), Expression::Number(2.0)] });
+    }
+
+    #[test]
+    fn test_scientific_notation() {
+        let number = |formula| match parse_cell_from_str(formula) {
+            Some(Expression::Number(number)) => number,
+            expr => panic!("expected a number, got {:?}", expr),
+        };
+
+        assert_eq!(number("=1e3"), 1000.0);
+        assert_eq!(number("=2.5E-4"), 0.00025);
+        assert_eq!(number("=.5e+2"), 50.0);
+        assert_eq!(parse_cell_from_str("=1.5e3%"), Some(Expression::Percentage(1500.0)));
+        assert_eq!(
+            parse_cell_from_str("=1e3+1"),
+            Some(Expression::Plus { args: vec![Expression::Number(1000.0), Expression::Number(1.0)] }),
+        );
+    }
+
+    #[test]
+    fn test_underscores_in_numbers() {
+        assert_eq!(parse_cell_from_str("=1_000_000"), Some(Expression::Number(1000000.0)));
+        assert_eq!(parse_cell_from_str("=1_000.000_5"), Some(Expression::Number(1000.0005)));
+        assert_eq!(parse_cell_from_str("=2_5%"), Some(Expression::Percentage(25.0)));
+
+        let (rows, _) = parse("=1__0|=1_|=_1|=1_.5").unwrap();
+        assert_eq!(rows[0].len(), 4);
+        for (cell, source) in rows[0].iter().zip(["=1__0", "=1_", "=_1", "=1_.5"]) {
+            assert_eq!(cell, &Expression::String(source.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_boolean_literals() {
+        assert_eq!(parse_cell_from_str("=true"), Some(Expression::String("true".to_string())));
+        assert_eq!(parse_cell_from_str("=FALSE"), Some(Expression::String("false".to_string())));
+        assert!(matches!(parse_cell_from_str("=trueish(1)"), Some(Expression::Function { .. })));
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        let negate = |expr| Expression::Negate(Box::new(expr));
+
+        assert_eq!(parse_cell_from_str("=-5"), Some(negate(Expression::Number(5.0))));
+        assert_eq!(
+            parse_cell_from_str("=3*-2"),
+            Some(Expression::Multiply { args: vec![Expression::Number(3.0), negate(Expression::Number(2.0))] }),
+        );
+        assert_eq!(parse_cell_from_str("=--A1"), Some(negate(negate(Expression::CellReference(CellReference {
+            name: "A1".to_string(),
+            column_name: "A".to_string(),
+            column: 1,
+            row: 1,
+            absolute_column: false,
+            absolute_row: false,
+        })))));
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let number = Expression::Number;
+
+        assert_eq!(
+            parse_cell_from_str("=1+2*3"),
+            Some(Expression::Plus { args: vec![number(1.0), Expression::Multiply { args: vec![number(2.0), number(3.0)] }] }),
+        );
+        assert_eq!(
+            parse_cell_from_str("=8-2-1"),
+            Some(Expression::Minus { args: vec![Expression::Minus { args: vec![number(8.0), number(2.0)] }, number(1.0)] }),
+        );
+        assert_eq!(
+            parse_cell_from_str("=2^3^2"),
+            Some(Expression::Power { args: vec![number(2.0), Expression::Power { args: vec![number(3.0), number(2.0)] }] }),
+        );
+        assert!(matches!(
+            parse_cell_from_str("=E^^2"),
+            Some(Expression::Power { args }) if matches!(args[0], Expression::CopyEvaluated(_)),
+        ));
+    }
+
+    #[test]
+    fn test_pipe() {
+        assert_eq!(parse_cell_from_str(r#"=split(D2,",") |> sum"#), parse_cell_from_str(r#"=sum(split(D2, ","))"#));
+        assert_eq!(parse_cell_from_str("=1+2 |> max(7) |> abs"), parse_cell_from_str("=abs(max(1+2, 7))"));
+        assert_eq!(parse_cell_from_str("=(A1 |> abs)*2"), parse_cell_from_str("=abs(A1)*2"));
+
+        let (cells, _) = parse("=A1|>abs|3\n").unwrap();
+        assert_eq!(cells[0].len(), 2);
+        assert_eq!(Some(cells[0][0].clone()), parse_cell_from_str("=abs(A1)"));
+    }
+
+    #[test]
+    fn test_reference_numbers_too_large() {
+        let error = parse("1|=A99999999999999999999999\n").unwrap_err();
+
+        assert!(matches!(error, ParseError::Syntax(_)));
+        assert!(error.to_string().contains("99999999999999999999999 is too large for a row number or offset"));
+        assert_eq!(parse_cell_from_str("=A99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn test_column_names_too_large() {
+        for input in ["=ZZZZZZZZZZZZZZZZ1", "=ZZZZZZZZZZZZZZZZ^v", "=ZZZZZZZZZZZZZZZZ^"] {
+            let error = parse(input).unwrap_err();
+
+            assert!(matches!(error, ParseError::Syntax(_)), "{}", input);
+            assert!(error.to_string().contains("ZZZZZZZZZZZZZZZZ is too large for a column name"), "{}", input);
+            assert_eq!(parse_cell_from_str(input), None, "{}", input);
         }
     }
-    unreachable!()
+
+    #[test]
+    fn test_duplicate_label() {
+        let error = parse("!a|!b\n1|2\n!c|!a\n").unwrap_err();
+
+        assert_eq!(error, ParseError::DuplicateLabel { label: "a".to_string(), first: (1, 1), second: (3, 2) });
+    }
+
+    #[test]
+    fn test_row_reference() {
+        assert_eq!(
+            parse_cell_from_str("=sum(2:2)"),
+            Some(Expression::Function {
+                name: "sum".to_string(),
+                params: vec![Expression::RowReference(RowReference { name: "2:2".to_string(), first: 2, last: 2 })],
+            }),
+        );
+        assert_eq!(
+            parse_cell_from_str("=4:3"),
+            Some(Expression::RowReference(RowReference { name: "4:3".to_string(), first: 3, last: 4 })),
+        );
+    }
+
+    #[test]
+    fn test_absolute_references() {
+        let anchors = |formula| match parse_cell_from_str(formula) {
+            Some(Expression::CellReference(cell_ref)) => (cell_ref.name, cell_ref.column, cell_ref.row, cell_ref.absolute_column, cell_ref.absolute_row),
+            expr => panic!("expected a cell reference, got {:?}", expr),
+        };
+
+        assert_eq!(anchors("=B2"), ("B2".to_string(), 2, 2, false, false));
+        assert_eq!(anchors("=$B$2"), ("$B$2".to_string(), 2, 2, true, true));
+        assert_eq!(anchors("=$B2"), ("$B2".to_string(), 2, 2, true, false));
+        assert_eq!(anchors("=B$2"), ("B$2".to_string(), 2, 2, false, true));
+    }
 }